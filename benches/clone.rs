@@ -0,0 +1,25 @@
+//! The cost of cloning a `Connection`, which should not depend on the size of the cluster: the
+//! clones share the driver task owning the slot map and the node connections.
+
+mod support;
+
+use std::{hint::black_box, time::Instant};
+
+const CLONES: u32 = 1_000_000;
+
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for nodes in [1, 16, 256, 1024] {
+        let connection = runtime.block_on(support::connect(nodes));
+        let start = Instant::now();
+        for _ in 0..CLONES {
+            black_box(connection.clone());
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:>5} nodes: {:>6.1} ns per clone",
+            nodes,
+            elapsed.as_nanos() as f64 / f64::from(CLONES)
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! An in-memory cluster for the benchmarks, whose nodes answer every command right away.
+
+use futures::future;
+use redis_cluster_async::{
+    redis::{aio::ConnectionLike, Cmd, ConnectionAddr, IntoConnectionInfo, RedisFuture, Value},
+    Client, Connect, Connection,
+};
+
+const FIRST_PORT: u16 = 7000;
+
+// A node of a cluster of `nodes` masters, each serving an even share of the slots. The size of
+// the cluster is given by the host name, `nodes-<count>`.
+#[derive(Clone)]
+pub struct BenchConnection {
+    nodes: u16,
+}
+
+impl Connect for BenchConnection {
+    fn connect<'a, T>(info: T) -> RedisFuture<'a, Self>
+    where
+        T: IntoConnectionInfo + Send + 'a,
+    {
+        let info = info.into_connection_info().unwrap();
+        let nodes = match &info.addr {
+            ConnectionAddr::Tcp(host, _) => host["nodes-".len()..].parse().unwrap(),
+            _ => unreachable!(),
+        };
+        Box::pin(future::ok(BenchConnection { nodes }))
+    }
+}
+
+impl BenchConnection {
+    fn slots(&self) -> Value {
+        let share = 16384 / self.nodes;
+        let slots = (0..self.nodes)
+            .map(|node| {
+                let end = if node + 1 == self.nodes {
+                    16383
+                } else {
+                    (node + 1) * share - 1
+                };
+                Value::Bulk(vec![
+                    Value::Int(i64::from(node * share)),
+                    Value::Int(i64::from(end)),
+                    Value::Bulk(vec![
+                        Value::Data(format!("nodes-{}", self.nodes).into_bytes()),
+                        Value::Int(i64::from(FIRST_PORT + node)),
+                    ]),
+                ])
+            })
+            .collect();
+        Value::Bulk(slots)
+    }
+
+    fn respond(&self, cmd: &Cmd) -> Value {
+        match cmd.args_iter().next() {
+            Some(redis::Arg::Simple(b"CLUSTER")) => self.slots(),
+            Some(redis::Arg::Simple(b"PING")) => Value::Status("PONG".into()),
+            _ => Value::Okay,
+        }
+    }
+}
+
+impl ConnectionLike for BenchConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(future::ok(self.respond(cmd)))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let values = pipeline
+            .cmd_iter()
+            .skip(offset)
+            .take(count)
+            .map(|cmd| self.respond(cmd))
+            .collect();
+        Box::pin(future::ok(values))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+// A connection to a cluster of `nodes` masters, connected to each of them
+pub async fn connect(nodes: u16) -> Connection<BenchConnection> {
+    let client = Client::open(vec![format!("redis://nodes-{}:{}", nodes, FIRST_PORT)]).unwrap();
+    let connection = client.get_generic_connection().await.unwrap();
+    connection.warm_up().await.unwrap();
+    connection
+}
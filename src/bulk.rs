@@ -0,0 +1,170 @@
+//! Writing many keys at once with pipelines of `SET` and `MSET` sent to every master concurrently.
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures::future;
+use redis::{aio::ConnectionLike, cmd, pipe, RedisError, RedisResult, ToRedisArgs};
+
+use crate::{Connection, Routing};
+
+/// What `Connection::bulk_set` wrote.
+#[derive(Debug, Default)]
+pub struct BulkSetReport {
+    written: u64,
+    failed: u64,
+    errors: Vec<(Option<String>, RedisError)>,
+}
+
+impl BulkSetReport {
+    /// The number of pairs written.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The number of pairs which may not have been written because the pipeline carrying them
+    /// failed.
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    /// The error of each pipeline which failed, along with the address of the node it was sent
+    /// to (`None` if the error did not come from a node).
+    pub fn errors(&self) -> &[(Option<String>, RedisError)] {
+        &self.errors
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Set the keys of `pairs` to their values, e.g. to load a dataset. The pairs are taken
+    /// `batch_size` at a time from the iterator, so only one batch is held in memory. The pairs of
+    /// a batch in the same slot (as mapped by `Client::set_hash_key`) are set with one `MSET`,
+    /// the others with `SET`, and the batch is sent as one pipeline per master, the masters being
+    /// written concurrently (see `Client::set_fanout_concurrency`). The next batch is taken once
+    /// every pipeline completed.
+    ///
+    /// A failed pipeline does not stop the load: its pairs are counted as failed and its error is
+    /// reported along with the node it was sent to, the pairs it carried may have been written
+    /// partly. The error is only returned if the connection itself failed.
+    pub async fn bulk_set<I, K, V>(&self, pairs: I, batch_size: usize) -> RedisResult<BulkSetReport>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: ToRedisArgs,
+    {
+        let mut report = BulkSetReport::default();
+        let mut pairs = pairs.into_iter().peekable();
+        while pairs.peek().is_some() {
+            let batch: Vec<_> = pairs.by_ref().take(batch_size.max(1)).collect();
+            self.set_batch(batch, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    async fn set_batch<K, V>(
+        &self,
+        batch: Vec<(K, V)>,
+        report: &mut BulkSetReport,
+    ) -> RedisResult<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToRedisArgs,
+    {
+        let hasher = self.slot_hasher().await?;
+        let mut slots: Vec<u16> = Vec::new();
+        let mut groups: Vec<Vec<(K, V)>> = Vec::new();
+        let mut group_of: HashMap<u16, usize> = HashMap::new();
+        for (key, value) in batch {
+            let slot = hasher.slot_for_key(key.as_ref());
+            let group = *group_of.entry(slot).or_insert_with(|| {
+                slots.push(slot);
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[group].push((key, value));
+        }
+
+        // One pipeline per master, built here so the driver sends it as is instead of copying it
+        // to split it by node. The slots without a known master while the slots are being
+        // refreshed share a pipeline, which is split once they are known.
+        let masters = self.slot_masters(slots).await?;
+        // The pipeline of each master, with the number of pairs of each of its commands
+        let mut pipelines: Vec<(Option<String>, redis::Pipeline, Vec<u64>)> = Vec::new();
+        for (group, master) in groups.into_iter().zip(masters) {
+            let index = match pipelines.iter().position(|(node, ..)| *node == master) {
+                Some(index) => index,
+                None => {
+                    pipelines.push((master, pipe(), Vec::new()));
+                    pipelines.len() - 1
+                }
+            };
+            let mut set = match group.len() {
+                1 => cmd("SET"),
+                _ => cmd("MSET"),
+            };
+            for (key, value) in &group {
+                set.arg(key.as_ref()).arg(value);
+            }
+            let (_, pipeline, pair_counts) = &mut pipelines[index];
+            pipeline.add_command(set);
+            pair_counts.push(group.len() as u64);
+        }
+
+        let results = future::join_all(pipelines.into_iter().map(
+            |(master, pipeline, pair_counts)| {
+                let mut connection = Connection(self.0.clone());
+                async move {
+                    // Routed by its keys rather than to the master so the redirections are
+                    // followed
+                    let (pipeline, count) = (Arc::new(pipeline), pair_counts.len());
+                    let routing = Routing::FanOutKeys;
+                    let result = connection
+                        .dispatch_shared_pipeline(pipeline, 0, count, routing)
+                        .await;
+                    (master, pair_counts, result)
+                }
+            },
+        ))
+        .await;
+
+        for (master, pair_counts, result) in results {
+            let total: u64 = pair_counts.iter().sum();
+            let err = match result {
+                Ok(_) => {
+                    report.written += total;
+                    continue;
+                }
+                Err(err) => err,
+            };
+            if err.is_connection_dropped() && err.node().is_none() {
+                // The connection was dropped, the next batches would fail the same way
+                return Err(err.into_inner());
+            }
+            // The pipeline is split by node if its slots moved since their master was looked up,
+            // or if they had none
+            match err.partial() {
+                Some(partial) => {
+                    for part in partial.parts() {
+                        let pairs: u64 = part.positions().iter().map(|&i| pair_counts[i]).sum();
+                        match part.result() {
+                            Ok(_) => report.written += pairs,
+                            Err(part_err) => {
+                                report.failed += pairs;
+                                let node = part.node().map(str::to_string);
+                                report.errors.push((node, crate::copy_error(part_err)));
+                            }
+                        }
+                    }
+                }
+                None => {
+                    report.failed += total;
+                    let node = err.node().map(str::to_string).or(master);
+                    report.errors.push((node, err.into_inner()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! The state of the cluster as reported by `CLUSTER INFO`.
+
+use std::collections::HashMap;
+
+use redis::{aio::ConnectionLike, cmd, RedisResult};
+
+use crate::Connection;
+
+/// The fields of `CLUSTER INFO`, see `Connection::cluster_info`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClusterInfo {
+    fields: HashMap<String, String>,
+}
+
+impl ClusterInfo {
+    fn parse(info: &str) -> ClusterInfo {
+        let fields = info
+            .lines()
+            .filter_map(|line| line.trim_end().split_once(':'))
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect();
+        ClusterInfo { fields }
+    }
+
+    // The integer value of `field`, 0 if the node did not report it
+    fn count(&self, field: &str) -> u64 {
+        self.get(field)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the node can serve queries (`cluster_state:ok`).
+    pub fn is_ok(&self) -> bool {
+        self.state() == "ok"
+    }
+
+    /// `ok`, or `fail` if some slots are unassigned, failing or not reachable from the node.
+    pub fn state(&self) -> &str {
+        self.get("cluster_state").unwrap_or("fail")
+    }
+
+    /// The number of slots assigned to a master (`cluster_slots_assigned`).
+    pub fn slots_assigned(&self) -> u64 {
+        self.count("cluster_slots_assigned")
+    }
+
+    /// The number of slots whose master is reachable (`cluster_slots_ok`).
+    pub fn slots_ok(&self) -> u64 {
+        self.count("cluster_slots_ok")
+    }
+
+    /// The number of slots whose master the node suspects to be failing (`cluster_slots_pfail`).
+    pub fn slots_pfail(&self) -> u64 {
+        self.count("cluster_slots_pfail")
+    }
+
+    /// The number of slots whose master the cluster agreed is failing (`cluster_slots_fail`).
+    pub fn slots_fail(&self) -> u64 {
+        self.count("cluster_slots_fail")
+    }
+
+    /// The number of nodes known by the node, handshaking ones included
+    /// (`cluster_known_nodes`).
+    pub fn known_nodes(&self) -> u64 {
+        self.count("cluster_known_nodes")
+    }
+
+    /// The number of masters serving at least one slot (`cluster_size`).
+    pub fn size(&self) -> u64 {
+        self.count("cluster_size")
+    }
+
+    /// The epoch of the cluster (`cluster_current_epoch`).
+    pub fn current_epoch(&self) -> u64 {
+        self.count("cluster_current_epoch")
+    }
+
+    /// The epoch of the node (`cluster_my_epoch`).
+    pub fn my_epoch(&self) -> u64 {
+        self.count("cluster_my_epoch")
+    }
+
+    /// The value of any field, e.g. `cluster_stats_messages_sent`.
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.fields.get(field).map(String::as_str)
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// The state of the cluster as seen by a random node, asked with `CLUSTER INFO`. The command
+    /// is retried on another node if the node fails, like any command without keys.
+    pub async fn cluster_info(&mut self) -> RedisResult<ClusterInfo> {
+        let info: String = cmd("CLUSTER").arg("INFO").query_async(self).await?;
+        Ok(ClusterInfo::parse(&info))
+    }
+}
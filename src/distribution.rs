@@ -0,0 +1,108 @@
+//! Reporting how the keys are spread over the slots and the masters of the cluster.
+
+use std::collections::HashMap;
+
+use futures::prelude::*;
+use redis::{aio::ConnectionLike, cmd, pipe, FromRedisValue, RedisResult};
+
+use crate::Connection;
+
+/// The number of keys of a slot, see `Connection::slot_key_counts`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlotCount {
+    slot: u16,
+    node: String,
+    keys: i64,
+}
+
+impl SlotCount {
+    pub fn slot(&self) -> u16 {
+        self.slot
+    }
+
+    /// The address of the master serving the slot.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// The number of keys in the slot (`CLUSTER COUNTKEYSINSLOT`).
+    pub fn keys(&self) -> i64 {
+        self.keys
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// The number of keys of every slot served by a master, ordered by slot. Each master runs
+    /// `CLUSTER COUNTKEYSINSLOT` for its slots in one pipeline, the masters being queried
+    /// concurrently. The counts are taken one slot after the other and keys may be written or
+    /// migrated in the meantime, so they are approximate. Fails if any of the masters fails.
+    ///
+    /// This sends one command per slot, `Connection::key_distribution` is much lighter when the
+    /// counts per master are enough.
+    pub async fn slot_key_counts(&mut self) -> RedisResult<Vec<SlotCount>> {
+        let mut shards: Vec<(String, Vec<(u16, u16)>)> = self
+            .topology_snapshot()
+            .await?
+            .shards()
+            .iter()
+            .map(|shard| (shard.master().to_string(), shard.slots().to_vec()))
+            .collect();
+        if shards.is_empty() {
+            // The slots are being refreshed, ask the cluster
+            for slot in crate::get_slots(self).await? {
+                let range = (slot.start(), slot.end());
+                match shards
+                    .iter_mut()
+                    .find(|(master, _)| master == slot.master())
+                {
+                    Some((_, ranges)) => ranges.push(range),
+                    None => shards.push((slot.master().to_string(), vec![range])),
+                }
+            }
+        }
+
+        let counts = future::try_join_all(shards.into_iter().map(|(master, ranges)| {
+            let mut connection = Connection(self.0.clone());
+            async move {
+                let slots: Vec<u16> = ranges
+                    .iter()
+                    .flat_map(|&(start, end)| start..=end)
+                    .collect();
+                // The commands are routed by their slot, to the master serving it
+                let mut counts = pipe();
+                for slot in &slots {
+                    counts.add_command(cmd("CLUSTER").arg("COUNTKEYSINSLOT").arg(*slot).clone());
+                }
+                let keys: Vec<i64> = counts.query_async(&mut connection).await?;
+                Ok::<_, redis::RedisError>(
+                    slots
+                        .into_iter()
+                        .zip(keys)
+                        .map(|(slot, keys)| SlotCount {
+                            slot,
+                            node: master.clone(),
+                            keys,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+        }))
+        .await?;
+        let mut counts: Vec<_> = counts.into_iter().flatten().collect();
+        counts.sort_by_key(|count| count.slot);
+        Ok(counts)
+    }
+
+    /// The number of keys of every master by address, its `DBSIZE`. See `Connection::dbsize` for
+    /// their sum. Fails if any of the masters fails.
+    pub async fn key_distribution(&mut self) -> RedisResult<HashMap<String, i64>> {
+        self.broadcast(&cmd("DBSIZE"))
+            .await?
+            .into_iter()
+            .map(|(master, result)| Ok((master, i64::from_redis_value(&result?)?)))
+            .collect()
+    }
+}
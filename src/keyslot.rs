@@ -0,0 +1,62 @@
+//! Checking the slot computed for a key against the slot the cluster computes for it.
+
+use log::warn;
+use redis::{aio::ConnectionLike, cmd, RedisResult};
+
+use crate::Connection;
+
+/// The slot of a key computed by the connection and by a node, see `Connection::verify_keyslot`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeySlotCheck {
+    local: u16,
+    server: u16,
+}
+
+impl KeySlotCheck {
+    /// The slot the connection routes the key by.
+    pub fn local(&self) -> u16 {
+        self.local
+    }
+
+    /// The slot a node returned for the key (`CLUSTER KEYSLOT`).
+    pub fn server(&self) -> u16 {
+        self.server
+    }
+
+    /// Whether both slots are the same.
+    pub fn matches(&self) -> bool {
+        self.local == self.server
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Compute the slot of `key` as the connection routes it and ask a node for it with
+    /// `CLUSTER KEYSLOT`, e.g. to check a cluster against the hashing of the connection. A
+    /// mismatch is logged as a warning, it means the commands on the key are redirected or fail.
+    /// It is expected after `Client::set_slot_count` or `Client::set_hash_key` if the cluster does
+    /// not hash the keys the same way.
+    pub async fn verify_keyslot(&mut self, key: &[u8]) -> RedisResult<KeySlotCheck> {
+        let route = self.explain_route(cmd("EXISTS").arg(key)).await?;
+        let local = route
+            .slot()
+            .expect("EXISTS is routed by its key, it has a slot");
+        let server = cmd("CLUSTER")
+            .arg("KEYSLOT")
+            .arg(key)
+            .query_async(self)
+            .await?;
+        let check = KeySlotCheck { local, server };
+        if !check.matches() {
+            warn!(
+                "The slot of {} is {} but the cluster computes {}",
+                String::from_utf8_lossy(key),
+                local,
+                server
+            );
+        }
+        Ok(check)
+    }
+}
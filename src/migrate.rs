@@ -0,0 +1,81 @@
+//! Copying a key to another cluster (or node) with `DUMP` and `RESTORE`.
+
+use redis::{aio::ConnectionLike, cmd, pipe, RedisResult, ToRedisArgs};
+
+use crate::Connection;
+
+/// The options of the `RESTORE` sent by `Connection::migrate_key`.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    replace: bool,
+    idle_time: Option<u64>,
+}
+
+impl RestoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Overwrite the key if it already exists on the target (`REPLACE`), instead of failing with
+    /// a `BUSYKEY` error.
+    pub fn with_replace(mut self) -> Self {
+        self.replace = true;
+        self
+    }
+
+    /// Set the idle time of the restored key, in seconds (`IDLETIME`). Only used by the LRU
+    /// eviction policies, the key is otherwise restored as freshly accessed.
+    pub fn with_idle_time(mut self, seconds: u64) -> Self {
+        self.idle_time = Some(seconds);
+        self
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Copy `key` to `target`, typically a connection to another cluster: `DUMP` it from the
+    /// master serving it and `RESTORE` the serialized value on `target` with the same time to
+    /// live. Returns `false` without touching `target` if the key does not exist. The key is left
+    /// in place, delete it once copied to move it.
+    ///
+    /// The serialized value is passed on as is, but it can only be restored by a server running
+    /// the same or a later version of Redis.
+    pub async fn migrate_key<K, T>(
+        &mut self,
+        key: K,
+        target: &mut T,
+        options: RestoreOptions,
+    ) -> RedisResult<bool>
+    where
+        K: ToRedisArgs,
+        T: ConnectionLike,
+    {
+        let key = key.to_redis_args().concat();
+        let (payload, ttl): (Option<Vec<u8>>, i64) = pipe()
+            .cmd("DUMP")
+            .arg(&key)
+            .cmd("PTTL")
+            .arg(&key)
+            .query_async(self)
+            .await?;
+        // A `PTTL` of -2 means the key expired right after it was dumped
+        let payload = match payload {
+            Some(payload) if ttl != -2 => payload,
+            _ => return Ok(false),
+        };
+
+        // `PTTL` is -1 for a key without expiry, which `RESTORE` expects as 0
+        let mut restore = cmd("RESTORE");
+        restore.arg(&key).arg(ttl.max(0)).arg(payload);
+        if options.replace {
+            restore.arg("REPLACE");
+        }
+        if let Some(seconds) = options.idle_time {
+            restore.arg("IDLETIME").arg(seconds);
+        }
+        restore.query_async::<_, ()>(target).await?;
+        Ok(true)
+    }
+}
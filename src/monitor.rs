@@ -0,0 +1,69 @@
+//! Streaming the commands processed by a node with `MONITOR`.
+
+use std::io;
+
+use futures::{prelude::*, stream};
+use log::trace;
+use redis::{
+    aio::ConnectionLike, cmd, ConnectionInfo, ErrorKind, FromRedisValue, RedisError, RedisResult,
+    Value,
+};
+
+use crate::{
+    pubsub::{self, ValueStream, DEFAULT_CONFIRM_TIMEOUT},
+    runtime::Runtime,
+    ClusterParams, Connection, Message,
+};
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Stream the commands processed by `node`, a master or a replica of the slot map, with
+    /// `MONITOR`: one line per command, as the node logs them. `MONITOR` leaves the connection
+    /// streaming for good, so it runs over a connection of its own which is closed once the
+    /// stream is dropped, never over the connections shared by the requests. If that connection
+    /// can not be opened the stream yields the error and ends, it also ends once the connection
+    /// is lost. Monitoring slows the node down, keep it to debugging sessions.
+    pub fn monitor(&self, node: &str) -> impl Stream<Item = RedisResult<String>> {
+        let connection = Connection(self.0.clone());
+        let node = node.to_string();
+        let start = Box::pin(async move { connection.start_monitor(&node).await });
+        stream::once(start)
+            .map(|result| match result {
+                Ok(lines) => lines
+                    .filter_map(|line| future::ready(String::from_redis_value(&line).ok()))
+                    .map(Ok)
+                    .left_stream(),
+                Err(err) => stream::once(future::ready(Err(err))).right_stream(),
+            })
+            .flatten()
+    }
+
+    async fn start_monitor(&self, node: &str) -> RedisResult<ValueStream> {
+        let (info, params) = self.node_info(node).await?;
+        let (mut writer, mut values) = params
+            .with_connect_timeout(pubsub::connect(&info, &params))
+            .await?;
+        writer.write_cmd(&cmd("MONITOR")).await?;
+        // The error replies are left out of the stream, a refused `MONITOR` is only noticed by
+        // the missing confirmation
+        let timeout = params.response_timeout.unwrap_or(DEFAULT_CONFIRM_TIMEOUT);
+        match Runtime::locate().timeout(timeout, values.next()).await {
+            Ok(Some(Value::Okay)) => {
+                trace!("Monitoring {}", node);
+                Ok(values)
+            }
+            Ok(None) => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+            _ => Err(RedisError::from((
+                ErrorKind::ResponseError,
+                "MONITOR was not confirmed",
+                node.to_string(),
+            ))),
+        }
+    }
+
+    async fn node_info(&self, node: &str) -> RedisResult<(ConnectionInfo, ClusterParams)> {
+        self.request(|sender| Message::NodeInfo(node.to_string(), sender)).await?
+    }
+}
@@ -0,0 +1,1054 @@
+//! Pub/sub connections to the cluster, see `Client::get_pubsub`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either, FusedFuture},
+    prelude::*,
+    stream::{self, BoxStream},
+};
+use log::{trace, warn};
+use rand::{seq::SliceRandom, thread_rng};
+use redis::{
+    Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Msg, RedisConnectionInfo, RedisError,
+    RedisResult, ToRedisArgs, Value,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{runtime::Runtime, ClusterParams};
+
+// How long a node may take to confirm a `SSUBSCRIBE` (or `MONITOR`) if no response timeout is
+// set
+pub(crate) const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often `KeyEvents` looks up the masters if no topology refresh interval is set
+const DEFAULT_MASTERS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) type ValueStream = Pin<Box<dyn Stream<Item = Value> + Send>>;
+
+/// A pub/sub connection to the cluster.
+///
+/// Messages published with `PUBLISH` are broadcast to every node of the cluster, so the
+/// subscriptions are made on a single (random) node. If the connection to that node is lost
+/// another node is connected to and the active subscriptions are issued again. Messages published
+/// while no node is connected are lost.
+///
+/// `PubSub` is a `Stream` of the received messages. It ends only if the connection can not be
+/// driven anymore, which does not happen as long as the handle is alive. See
+/// `PubSub::on_reconnect` to be told about the gaps.
+pub struct PubSub(Handle, Arc<Mutex<Option<ReconnectCallback>>>);
+
+type ReconnectCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// The channel to the task driving the node connection(s) of a `PubSub` or `SPubSub`.
+struct Handle {
+    commands: mpsc::UnboundedSender<Subscription>,
+    messages: mpsc::UnboundedReceiver<Msg>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SubscriptionKind {
+    Subscribe,
+    PSubscribe,
+    Unsubscribe,
+    PUnsubscribe,
+    SSubscribe,
+    SUnsubscribe,
+}
+
+struct Subscription {
+    kind: SubscriptionKind,
+    names: Vec<Vec<u8>>,
+    sender: oneshot::Sender<RedisResult<()>>,
+}
+
+impl PubSub {
+    pub(crate) async fn new(
+        initial_nodes: Vec<ConnectionInfo>,
+        params: ClusterParams,
+    ) -> RedisResult<PubSub> {
+        let connection = connect_any(&initial_nodes, &params).await?;
+        let on_reconnect = Arc::new(Mutex::new(None));
+        let handle = Handle::spawn({
+            let on_reconnect = on_reconnect.clone();
+            |commands, messages| {
+                run(
+                    initial_nodes,
+                    params,
+                    connection,
+                    on_reconnect,
+                    commands,
+                    messages,
+                )
+            }
+        });
+        Ok(PubSub(handle, on_reconnect))
+    }
+
+    /// Set a function called each time the connection was lost and the channels and patterns
+    /// subscribed to were issued again on a new node, e.g. to fetch again the state the messages
+    /// published in the meantime would have updated since they are lost. It is called from the
+    /// task driving the connection, before the messages received over the new connection are
+    /// yielded, and should return quickly.
+    pub fn on_reconnect(&mut self, callback: impl Fn() + Send + Sync + 'static) {
+        *self.1.lock().unwrap() = Some(Arc::new(callback));
+    }
+
+    /// Subscribe to one or more channels.
+    ///
+    /// Returns once the command has been written to the node, messages published before the node
+    /// processed it are not received.
+    pub async fn subscribe<T: ToRedisArgs>(&mut self, channel: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::Subscribe, channel).await
+    }
+
+    /// Subscribe to one or more channel patterns.
+    pub async fn psubscribe<T: ToRedisArgs>(&mut self, pattern: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::PSubscribe, pattern).await
+    }
+
+    /// Unsubscribe from one or more channels. Messages which were already received for these
+    /// channels may still be yielded by the stream.
+    pub async fn unsubscribe<T: ToRedisArgs>(&mut self, channel: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::Unsubscribe, channel).await
+    }
+
+    /// Unsubscribe from one or more channel patterns.
+    pub async fn punsubscribe<T: ToRedisArgs>(&mut self, pattern: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::PUnsubscribe, pattern).await
+    }
+}
+
+impl Stream for PubSub {
+    type Item = Msg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Msg>> {
+        self.0.messages.poll_recv(cx)
+    }
+}
+
+impl Handle {
+    fn spawn<F, Fut>(f: F) -> Handle
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<Subscription>, mpsc::UnboundedSender<Msg>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (messages_tx, messages) = mpsc::unbounded_channel();
+        Runtime::locate().spawn(f(commands_rx, messages_tx));
+        Handle { commands, messages }
+    }
+
+    async fn send<T: ToRedisArgs>(&mut self, kind: SubscriptionKind, names: T) -> RedisResult<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.commands
+            .send(Subscription {
+                kind,
+                names: names.to_redis_args(),
+                sender,
+            })
+            .map_err(|_| closed())?;
+        receiver.await.unwrap_or_else(|_| Err(closed()))
+    }
+}
+
+fn closed() -> RedisError {
+    RedisError::from(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "pub/sub connection task has stopped",
+    ))
+}
+
+/// The channels and patterns which are subscribed to, issued again after a reconnect.
+#[derive(Default)]
+struct Subscriptions {
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+}
+
+impl Subscriptions {
+    fn apply(&mut self, kind: SubscriptionKind, names: &[Vec<u8>]) {
+        let (set, subscribe) = match kind {
+            SubscriptionKind::Subscribe => (&mut self.channels, true),
+            SubscriptionKind::PSubscribe => (&mut self.patterns, true),
+            SubscriptionKind::Unsubscribe => (&mut self.channels, false),
+            SubscriptionKind::PUnsubscribe => (&mut self.patterns, false),
+            SubscriptionKind::SSubscribe | SubscriptionKind::SUnsubscribe => {
+                unreachable!("Shard channels are handled by `SPubSub`")
+            }
+        };
+        if subscribe {
+            set.extend(names.iter().cloned());
+        } else if names.is_empty() {
+            // Without arguments every channel (or pattern) is unsubscribed from
+            set.clear();
+        } else {
+            for name in names {
+                set.remove(name);
+            }
+        }
+    }
+
+    fn commands(&self) -> Vec<Cmd> {
+        let mut commands = Vec::new();
+        if !self.channels.is_empty() {
+            commands.push(subscription_cmd(
+                SubscriptionKind::Subscribe,
+                &self.channels,
+            ));
+        }
+        if !self.patterns.is_empty() {
+            commands.push(subscription_cmd(
+                SubscriptionKind::PSubscribe,
+                &self.patterns,
+            ));
+        }
+        commands
+    }
+}
+
+fn subscription_cmd<'a>(
+    kind: SubscriptionKind,
+    names: impl IntoIterator<Item = &'a Vec<u8>>,
+) -> Cmd {
+    let mut cmd = Cmd::new();
+    cmd.arg(match kind {
+        SubscriptionKind::Subscribe => "SUBSCRIBE",
+        SubscriptionKind::PSubscribe => "PSUBSCRIBE",
+        SubscriptionKind::Unsubscribe => "UNSUBSCRIBE",
+        SubscriptionKind::PUnsubscribe => "PUNSUBSCRIBE",
+        SubscriptionKind::SSubscribe => "SSUBSCRIBE",
+        SubscriptionKind::SUnsubscribe => "SUNSUBSCRIBE",
+    });
+    for name in names {
+        cmd.arg(&name[..]);
+    }
+    cmd
+}
+
+// Drives the connection to a node: forwards the subscription changes and the received messages,
+// and reconnects once the node is lost. Stops when the `PubSub` handle is dropped.
+async fn run(
+    nodes: Vec<ConnectionInfo>,
+    params: ClusterParams,
+    connection: (SharedWriter, ValueStream),
+    on_reconnect: Arc<Mutex<Option<ReconnectCallback>>>,
+    mut commands: mpsc::UnboundedReceiver<Subscription>,
+    messages: mpsc::UnboundedSender<Msg>,
+) {
+    let mut subscriptions = Subscriptions::default();
+    let mut connection = Some(connection);
+    let mut failures = 0;
+    loop {
+        let reconnected = connection.is_none();
+        let (mut writer, mut stream) = match connection.take() {
+            Some(connection) => connection,
+            None => match connect_any(&nodes, &params).await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    failures += 1;
+                    warn!("Unable to reconnect pub/sub connection: {}", err);
+                    let delay = params.retry_policy.delay(failures);
+                    if !back_off(delay, &mut commands, &mut subscriptions).await {
+                        return;
+                    }
+                    continue;
+                }
+            },
+        };
+
+        let mut resubscribed = Ok(());
+        for cmd in &subscriptions.commands() {
+            resubscribed = writer.write_cmd(cmd).await;
+            if resubscribed.is_err() {
+                break;
+            }
+        }
+        if let Err(err) = resubscribed {
+            failures += 1;
+            warn!("Unable to restore pub/sub subscriptions: {}", err);
+            let delay = params.retry_policy.delay(failures);
+            if !back_off(delay, &mut commands, &mut subscriptions).await {
+                return;
+            }
+            continue;
+        }
+        failures = 0;
+        if reconnected {
+            let callback = on_reconnect.lock().unwrap().clone();
+            if let Some(callback) = callback {
+                callback();
+            }
+        }
+
+        loop {
+            let next = future::select(Box::pin(commands.recv()), stream.next()).await;
+            match next {
+                Either::Left((
+                    Some(Subscription {
+                        kind,
+                        names,
+                        sender,
+                    }),
+                    _,
+                )) => {
+                    subscriptions.apply(kind, &names);
+                    let result = writer.write_cmd(&subscription_cmd(kind, &names)).await;
+                    let failed = result.is_err();
+                    let _ = sender.send(result);
+                    if failed {
+                        break;
+                    }
+                }
+                Either::Left((None, _)) => return,
+                Either::Right((Some(value), _)) => {
+                    if let Some(msg) = Msg::from_value(&value) {
+                        if messages.send(msg).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Either::Right((None, _)) => {
+                    trace!("Pub/sub connection lost, reconnecting");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Wait for `delay` before connecting again. The subscription changes made in the meantime are
+// kept track of and issued once a node is connected. Returns `false` once the `PubSub` handle is
+// dropped.
+async fn back_off(
+    delay: Duration,
+    commands: &mut mpsc::UnboundedReceiver<Subscription>,
+    subscriptions: &mut Subscriptions,
+) -> bool {
+    let mut sleep = Runtime::locate().sleep(delay);
+    loop {
+        match future::select(sleep, Box::pin(commands.recv())).await {
+            Either::Left(_) => return true,
+            Either::Right((
+                Some(Subscription {
+                    kind,
+                    names,
+                    sender,
+                }),
+                rest,
+            )) => {
+                subscriptions.apply(kind, &names);
+                let _ = sender.send(Ok(()));
+                sleep = rest;
+            }
+            Either::Right((None, _)) => return false,
+        }
+    }
+}
+
+/// A sharded pub/sub connection to the cluster (`SSUBSCRIBE`, Redis 7 and later).
+///
+/// Contrary to the classic pub/sub, messages published with `SPUBLISH` are only delivered by the
+/// shard owning the slot of the channel, so every channel is subscribed to on the master serving
+/// its slot. When the slot moves to another node (after a resharding or a failover) the
+/// subscription is moved to the new owner. Messages published while a subscription is being moved
+/// are lost.
+///
+/// `SPubSub` is a `Stream` of the received messages.
+pub struct SPubSub(Handle);
+
+impl SPubSub {
+    pub(crate) async fn new(
+        initial_nodes: &[ConnectionInfo],
+        params: ClusterParams,
+    ) -> RedisResult<SPubSub> {
+        let config = crate::ConnectConfig::default();
+        let cluster = crate::Connection::new(initial_nodes, params.clone(), &config).await?;
+        let shards = Shards {
+            cluster,
+            params,
+            channels: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            pending: HashSet::new(),
+            nodes: HashMap::new(),
+            streams: stream::SelectAll::new(),
+            next_id: 0,
+        };
+        Ok(SPubSub(Handle::spawn(|commands, messages| {
+            run_sharded(shards, commands, messages)
+        })))
+    }
+
+    /// Subscribe to one or more shard channels.
+    ///
+    /// Returns once the command has been written to the nodes, messages published before the nodes
+    /// processed it are not received.
+    pub async fn ssubscribe<T: ToRedisArgs>(&mut self, channel: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::SSubscribe, channel).await
+    }
+
+    /// Unsubscribe from one or more shard channels.
+    pub async fn sunsubscribe<T: ToRedisArgs>(&mut self, channel: T) -> RedisResult<()> {
+        self.0.send(SubscriptionKind::SUnsubscribe, channel).await
+    }
+}
+
+impl Stream for SPubSub {
+    type Item = Msg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Msg>> {
+        self.0.messages.poll_recv(cx)
+    }
+}
+
+// Identifies a connection to a node, so the end of a connection which has been replaced is not
+// mistaken for the end of its replacement
+type ConnectionId = u64;
+
+/// The shard channels of a `SPubSub` and the nodes they are subscribed on.
+struct Shards {
+    // Used to look up the owners of the slots
+    cluster: crate::Connection,
+    params: ClusterParams,
+    channels: HashMap<Vec<u8>, ConnectionId>,
+    // The channels whose `SSUBSCRIBE` has not been confirmed yet. Error replies (such as `MOVED`)
+    // are not yielded by the message stream, so an unconfirmed subscription is moved once the
+    // confirmation timeout elapses.
+    unconfirmed: HashMap<Vec<u8>, Instant>,
+    // The channels which must be subscribed to again on the current owner of their slot
+    pending: HashSet<Vec<u8>>,
+    nodes: HashMap<String, (ConnectionId, SharedWriter)>,
+    streams: stream::SelectAll<BoxStream<'static, (ConnectionId, Option<Value>)>>,
+    next_id: ConnectionId,
+}
+
+enum ShardEvent {
+    Subscription(Option<Subscription>),
+    Value(ConnectionId, Value),
+    Lost(ConnectionId),
+    Timer,
+}
+
+enum ShardPush {
+    Message(Msg),
+    Subscribed(Vec<u8>),
+    Unsubscribed(Vec<u8>),
+}
+
+impl Shards {
+    async fn subscribe(&mut self, names: Vec<Vec<u8>>) -> RedisResult<()> {
+        let slots = crate::get_slots(&mut self.cluster).await?;
+        let mut by_node = HashMap::<_, Vec<_>>::new();
+        for name in names {
+            let slot = self.params.slot_hasher.slot_for_key(&name);
+            let node = slots
+                .iter()
+                .find(|slot_data| slot_data.start() <= slot && slot <= slot_data.end())
+                .ok_or_else(|| {
+                    RedisError::from((
+                        ErrorKind::ClusterDown,
+                        "Slot is not served by any node",
+                        slot.to_string(),
+                    ))
+                })?
+                .master()
+                .to_string();
+            by_node.entry(node).or_default().push(name);
+        }
+
+        for (node, names) in by_node {
+            let (id, mut writer) = self.node(&node).await?;
+            if let Err(err) = writer
+                .write_cmd(&subscription_cmd(SubscriptionKind::SSubscribe, &names))
+                .await
+            {
+                self.nodes.remove(&node);
+                return Err(err);
+            }
+            let now = Instant::now();
+            for name in names {
+                self.pending.remove(&name);
+                self.unconfirmed.insert(name.clone(), now);
+                self.channels.insert(name, id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, names: Vec<Vec<u8>>) -> RedisResult<()> {
+        let names = if names.is_empty() {
+            // Without arguments every channel is unsubscribed from
+            self.channels.keys().chain(&self.pending).cloned().collect()
+        } else {
+            names
+        };
+        let mut by_connection = HashMap::<_, Vec<_>>::new();
+        for name in names {
+            self.pending.remove(&name);
+            self.unconfirmed.remove(&name);
+            if let Some(id) = self.channels.remove(&name) {
+                by_connection.entry(id).or_default().push(name);
+            }
+        }
+
+        for (id, names) in by_connection {
+            let writer = self
+                .nodes
+                .values_mut()
+                .find(|(node_id, _)| *node_id == id)
+                .map(|(_, writer)| writer);
+            // If the connection is gone so are its subscriptions
+            if let Some(writer) = writer {
+                writer
+                    .write_cmd(&subscription_cmd(SubscriptionKind::SUnsubscribe, &names))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn node(&mut self, node: &str) -> RedisResult<(ConnectionId, SharedWriter)> {
+        if let Some((id, writer)) = self.nodes.get(node) {
+            return Ok((*id, writer.clone()));
+        }
+        let info = crate::get_connection_info(node, &self.params)?;
+        let (writer, stream) = self
+            .params
+            .with_connect_timeout(connect(&info, &self.params))
+            .await?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.streams.push(
+            stream
+                .map(move |value| (id, Some(value)))
+                .chain(stream::once(future::ready((id, None))))
+                .boxed(),
+        );
+        self.nodes.insert(node.to_string(), (id, writer.clone()));
+        Ok((id, writer))
+    }
+
+    // Move the channels subscribed on the connection `id` to the current owner of their slot
+    fn moved(&mut self, id: ConnectionId, names: impl IntoIterator<Item = Vec<u8>>) {
+        for name in names {
+            if self.channels.get(&name) == Some(&id) {
+                self.channels.remove(&name);
+                self.unconfirmed.remove(&name);
+                self.pending.insert(name);
+            }
+        }
+    }
+
+    fn confirmed(&mut self, id: ConnectionId, name: &[u8]) {
+        if self.channels.get(name) == Some(&id) {
+            self.unconfirmed.remove(name);
+        }
+    }
+
+    fn lost(&mut self, id: ConnectionId) {
+        self.nodes.retain(|_, (node_id, _)| *node_id != id);
+        let names = self
+            .channels
+            .iter()
+            .filter(|(_, channel_id)| **channel_id == id)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        self.moved(id, names);
+    }
+
+    // Move the subscriptions which were not confirmed in time
+    fn expire_unconfirmed(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let expired = self
+            .unconfirmed
+            .iter()
+            .filter(|(_, sent)| now.duration_since(**sent) >= timeout)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in expired {
+            self.unconfirmed.remove(&name);
+            if let Some(id) = self.channels.get(&name).copied() {
+                self.moved(id, Some(name));
+            }
+        }
+    }
+
+    // How long until the next unconfirmed subscription expires
+    fn next_expiry(&self, timeout: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        self.unconfirmed
+            .values()
+            .map(|sent| timeout.saturating_sub(now.duration_since(*sent)))
+            .min()
+    }
+}
+
+fn shard_push(value: Value) -> Option<ShardPush> {
+    let mut items = match value {
+        Value::Bulk(items) if items.len() == 3 => items,
+        _ => return None,
+    };
+    let channel = match &items[1] {
+        Value::Data(channel) => channel.clone(),
+        _ => return None,
+    };
+    match &items[0] {
+        Value::Data(kind) if kind == b"smessage" => {
+            // Same layout as a classic message
+            items[0] = Value::Data(b"message".to_vec());
+            Msg::from_value(&Value::Bulk(items)).map(ShardPush::Message)
+        }
+        Value::Data(kind) if kind == b"ssubscribe" => Some(ShardPush::Subscribed(channel)),
+        Value::Data(kind) if kind == b"sunsubscribe" => Some(ShardPush::Unsubscribed(channel)),
+        _ => None,
+    }
+}
+
+// Drives the connections to the shards. A node sends `sunsubscribe` for the channels of a slot
+// which it stops serving, these channels (and those of a lost connection) are subscribed to again
+// on the new owner of the slot. Stops when the `SPubSub` handle is dropped.
+async fn run_sharded(
+    mut shards: Shards,
+    mut commands: mpsc::UnboundedReceiver<Subscription>,
+    messages: mpsc::UnboundedSender<Msg>,
+) {
+    let confirm_timeout = shards
+        .params
+        .response_timeout
+        .unwrap_or(DEFAULT_CONFIRM_TIMEOUT);
+    // Fires when the oldest unconfirmed subscription expires
+    let mut expiry = future::Fuse::terminated();
+    // Delays the next attempt to move the pending subscriptions after a failure
+    let mut backoff = future::Fuse::terminated();
+    let mut failures = 0;
+    loop {
+        let event = futures::select! {
+            subscription = commands.recv().fuse() => ShardEvent::Subscription(subscription),
+            next = shards.streams.next() => match next {
+                Some((id, Some(value))) => ShardEvent::Value(id, value),
+                Some((id, None)) => ShardEvent::Lost(id),
+                None => continue,
+            },
+            () = expiry => ShardEvent::Timer,
+            () = backoff => ShardEvent::Timer,
+        };
+        match event {
+            ShardEvent::Subscription(Some(Subscription {
+                kind,
+                names,
+                sender,
+            })) => {
+                let result = match kind {
+                    SubscriptionKind::SSubscribe => shards.subscribe(names).await,
+                    _ => shards.unsubscribe(names).await,
+                };
+                let _ = sender.send(result);
+            }
+            ShardEvent::Subscription(None) => return,
+            ShardEvent::Value(id, value) => {
+                let delivered = match shard_push(value) {
+                    Some(ShardPush::Message(msg)) => messages.send(msg).is_ok(),
+                    Some(ShardPush::Subscribed(name)) => {
+                        shards.confirmed(id, &name);
+                        true
+                    }
+                    Some(ShardPush::Unsubscribed(name)) => {
+                        trace!("Shard channel moved: {}", String::from_utf8_lossy(&name));
+                        shards.moved(id, Some(name));
+                        true
+                    }
+                    None => true,
+                };
+                if !delivered {
+                    return;
+                }
+            }
+            ShardEvent::Lost(id) => {
+                trace!("Sharded pub/sub connection lost");
+                shards.lost(id);
+            }
+            ShardEvent::Timer => (),
+        }
+
+        shards.expire_unconfirmed(confirm_timeout);
+        if backoff.is_terminated() && !shards.pending.is_empty() {
+            let names = shards.pending.iter().cloned().collect();
+            match shards.subscribe(names).await {
+                Ok(()) => failures = 0,
+                Err(err) => {
+                    failures += 1;
+                    warn!("Unable to move shard channel subscriptions: {}", err);
+                    backoff = Runtime::locate()
+                        .sleep(shards.params.retry_policy.delay(failures))
+                        .fuse();
+                }
+            }
+        }
+        if expiry.is_terminated() {
+            if let Some(next_expiry) = shards.next_expiry(confirm_timeout) {
+                expiry = Runtime::locate().sleep(next_expiry).fuse();
+            }
+        }
+    }
+}
+
+/// A key event notification, see `KeyEvents`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyEvent {
+    event: String,
+    key: Vec<u8>,
+}
+
+impl KeyEvent {
+    /// The name of the event, e.g. `expired` or `set`.
+    pub fn event(&self) -> &str {
+        &self.event
+    }
+
+    /// The key the event happened to.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+/// The key event notifications (`__keyevent@<db>__:<event>`) of the whole cluster, see
+/// `Client::get_keyevents`.
+///
+/// Notifications are only published by the node where the event happened, so the events are
+/// subscribed to on every master and the notifications of all the masters are merged. The masters
+/// are looked up again when a connection is lost and at the topology refresh interval (every 10
+/// seconds if none is set), so a master promoted by a failover is subscribed to as well. Events
+/// which happen on a master before it is subscribed to are lost.
+///
+/// The nodes only publish notifications if they are enabled with `notify-keyspace-events`, e.g.
+/// `CONFIG SET notify-keyspace-events Ex` for the expirations.
+///
+/// `KeyEvents` is a `Stream` of the received events.
+pub struct KeyEvents(mpsc::UnboundedReceiver<KeyEvent>);
+
+impl KeyEvents {
+    pub(crate) async fn new(
+        initial_nodes: &[ConnectionInfo],
+        params: ClusterParams,
+        db: i64,
+        events: &[&str],
+    ) -> RedisResult<KeyEvents> {
+        let config = crate::ConnectConfig::default();
+        let cluster = crate::Connection::new(initial_nodes, params.clone(), &config).await?;
+        let patterns = if events.is_empty() {
+            vec![format!("__keyevent@{}__:*", db).into_bytes()]
+        } else {
+            events
+                .iter()
+                .map(|event| format!("__keyevent@{}__:{}", db, event).into_bytes())
+                .collect()
+        };
+        let mut masters = Masters {
+            cluster,
+            params,
+            patterns,
+            nodes: HashMap::new(),
+        };
+        // The masters which could not be subscribed to are retried by the task
+        if let Err(err) = masters.sync().await {
+            if masters.nodes.is_empty() {
+                return Err(err);
+            }
+        }
+        let (events, receiver) = mpsc::unbounded_channel();
+        Runtime::locate().spawn(run_keyevents(masters, events));
+        Ok(KeyEvents(receiver))
+    }
+}
+
+impl Stream for KeyEvents {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<KeyEvent>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// The connections of a `KeyEvents` to the masters.
+struct Masters {
+    // Used to look up the masters
+    cluster: crate::Connection,
+    params: ClusterParams,
+    patterns: Vec<Vec<u8>>,
+    nodes: HashMap<String, (SharedWriter, ValueStream)>,
+}
+
+enum MastersEvent {
+    Value(Value),
+    Lost(String),
+    Refresh,
+    Closed,
+}
+
+impl Masters {
+    // Subscribe on the masters which are not subscribed to yet, and drop the connections to the
+    // nodes which are no longer masters
+    async fn sync(&mut self) -> RedisResult<()> {
+        let slots = crate::get_slots(&mut self.cluster).await?;
+        let masters = slots
+            .iter()
+            .map(|slot| slot.master().to_string())
+            .collect::<HashSet<_>>();
+        self.nodes.retain(|node, _| masters.contains(node));
+        let mut result = Ok(());
+        for master in masters {
+            if self.nodes.contains_key(&master) {
+                continue;
+            }
+            match subscribe_key_events(&master, &self.params, &self.patterns).await {
+                Ok(connection) => {
+                    self.nodes.insert(master, connection);
+                }
+                Err(err) => {
+                    warn!(
+                        "Unable to subscribe to the key events of {}: {}",
+                        master, err
+                    );
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_next_value(&mut self, cx: &mut task::Context) -> Poll<MastersEvent> {
+        for (node, (_, stream)) in &mut self.nodes {
+            if let Poll::Ready(value) = stream.poll_next_unpin(cx) {
+                return Poll::Ready(match value {
+                    Some(value) => MastersEvent::Value(value),
+                    None => MastersEvent::Lost(node.clone()),
+                });
+            }
+        }
+        Poll::Pending
+    }
+}
+
+async fn subscribe_key_events(
+    node: &str,
+    params: &ClusterParams,
+    patterns: &[Vec<u8>],
+) -> RedisResult<(SharedWriter, ValueStream)> {
+    let info = crate::get_connection_info(node, params)?;
+    let (mut writer, stream) = params.with_connect_timeout(connect(&info, params)).await?;
+    writer
+        .write_cmd(&subscription_cmd(SubscriptionKind::PSubscribe, patterns))
+        .await?;
+    Ok((writer, stream))
+}
+
+fn key_event(value: &Value) -> Option<KeyEvent> {
+    let msg = Msg::from_value(value)?;
+    let (_, event) = msg.get_channel_name().split_once("__:")?;
+    Some(KeyEvent {
+        event: event.to_string(),
+        key: msg.get_payload_bytes().to_vec(),
+    })
+}
+
+// Drives the connections to the masters and follows the changes of the masters. Stops when the
+// `KeyEvents` handle is dropped.
+async fn run_keyevents(mut masters: Masters, events: mpsc::UnboundedSender<KeyEvent>) {
+    let interval = masters
+        .params
+        .topology_refresh_interval
+        .unwrap_or(DEFAULT_MASTERS_REFRESH_INTERVAL);
+    let mut refresh = Runtime::locate().sleep(interval).fuse();
+    let mut failures = 0;
+    loop {
+        let event = futures::select! {
+            () = events.closed().fuse() => MastersEvent::Closed,
+            event = future::poll_fn(|cx| masters.poll_next_value(cx)).fuse() => event,
+            () = refresh => MastersEvent::Refresh,
+        };
+        match event {
+            MastersEvent::Value(value) => {
+                if let Some(event) = key_event(&value) {
+                    if events.send(event).is_err() {
+                        return;
+                    }
+                }
+                continue;
+            }
+            MastersEvent::Lost(node) => {
+                trace!("Key events connection to {} lost", node);
+                masters.nodes.remove(&node);
+            }
+            MastersEvent::Refresh => (),
+            MastersEvent::Closed => return,
+        }
+
+        let delay = match masters.sync().await {
+            Ok(()) => {
+                failures = 0;
+                interval
+            }
+            Err(_) => {
+                failures += 1;
+                masters.params.retry_policy.delay(failures)
+            }
+        };
+        refresh = Runtime::locate().sleep(delay).fuse();
+    }
+}
+
+async fn connect_any(
+    nodes: &[ConnectionInfo],
+    params: &ClusterParams,
+) -> RedisResult<(SharedWriter, ValueStream)> {
+    let mut nodes = nodes.iter().collect::<Vec<_>>();
+    nodes.shuffle(&mut thread_rng());
+    let mut last_err = None;
+    for info in nodes {
+        match params.with_connect_timeout(connect(info, params)).await {
+            Ok(connection) => return Ok(connection),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        RedisError::from((ErrorKind::InvalidClientConfig, "No node to connect to"))
+    }))
+}
+
+pub(crate) async fn connect(
+    info: &ConnectionInfo,
+    params: &ClusterParams,
+) -> RedisResult<(SharedWriter, ValueStream)> {
+    match info.addr {
+        ConnectionAddr::Tcp(ref host, port) => match Runtime::locate() {
+            #[cfg(feature = "tokio-comp")]
+            Runtime::Tokio => {
+                let stream = crate::tcp::connect_tokio(host, port, &params.socket).await?;
+                open(stream, &info.redis, params).await
+            }
+            #[cfg(feature = "async-std-comp")]
+            Runtime::AsyncStd => {
+                use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+                let stream = crate::tcp::connect_async_std(host, port, &params.socket).await?;
+                open(stream.compat(), &info.redis, params).await
+            }
+        },
+        #[cfg(feature = "tls-rustls")]
+        ConnectionAddr::TcpTls {
+            ref host,
+            port,
+            insecure,
+        } => {
+            let stream = crate::tls::connect_stream(host, port, insecure, &params.socket).await?;
+            open(stream, &info.redis, params).await
+        }
+        _ => Err(RedisError::from((
+            ErrorKind::InvalidClientConfig,
+            "Unsupported address for a pub/sub connection",
+        ))),
+    }
+}
+
+// The message stream of `redis::aio::PubSub` can not be stopped to send a command without losing
+// the data it buffered, so the commands are written to the socket directly while the connection
+// (which handles the authentication and the parsing of the messages) only reads from it. The
+// stream of `redis::aio::Monitor` is used since it yields every value sent by the node, including
+// the shard messages and the subscription confirmations, but not the error replies.
+async fn open<S>(
+    stream: S,
+    info: &RedisConnectionInfo,
+    params: &ClusterParams,
+) -> RedisResult<(SharedWriter, ValueStream)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(stream);
+    let writer = SharedWriter(Arc::new(Mutex::new(Box::pin(write))));
+    let mut connection = redis::aio::Connection::new(
+        info,
+        Halves {
+            read,
+            write: writer.clone(),
+        },
+    )
+    .await?;
+    crate::set_client_name(&mut connection, params).await;
+    crate::run_on_connect(&mut connection, params).await?;
+    Ok((
+        writer,
+        Box::pin(connection.into_monitor().into_on_message::<Value>()),
+    ))
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedWriter(Arc<Mutex<Pin<Box<dyn AsyncWrite + Send>>>>);
+
+impl SharedWriter {
+    pub(crate) async fn write_cmd(&mut self, cmd: &Cmd) -> RedisResult<()> {
+        self.write_all(&cmd.get_packed_command()).await?;
+        self.flush().await?;
+        Ok(())
+    }
+}
+
+impl AsyncWrite for SharedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().as_mut().poll_shutdown(cx)
+    }
+}
+
+struct Halves<R> {
+    read: R,
+    write: SharedWriter,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Halves<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin> AsyncWrite for Halves<R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_shutdown(cx)
+    }
+}
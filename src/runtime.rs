@@ -0,0 +1,83 @@
+//! The async runtime used to spawn the connection tasks and to wait, selected with the
+//! `tokio-comp` and `async-std-comp` features the same way as in the `redis` crate.
+
+use std::{future::Future, time::Duration};
+
+use futures::future::BoxFuture;
+
+#[cfg(not(any(feature = "tokio-comp", feature = "async-std-comp")))]
+compile_error!("The tokio-comp or async-std-comp feature is required");
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Runtime {
+    #[cfg(feature = "tokio-comp")]
+    Tokio,
+    #[cfg(feature = "async-std-comp")]
+    AsyncStd,
+}
+
+/// The error returned when `Runtime::timeout` elapses.
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+impl Runtime {
+    pub(crate) fn locate() -> Self {
+        #[cfg(all(feature = "tokio-comp", not(feature = "async-std-comp")))]
+        {
+            Runtime::Tokio
+        }
+
+        #[cfg(all(not(feature = "tokio-comp"), feature = "async-std-comp"))]
+        {
+            Runtime::AsyncStd
+        }
+
+        #[cfg(all(feature = "tokio-comp", feature = "async-std-comp"))]
+        {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                Runtime::Tokio
+            } else {
+                Runtime::AsyncStd
+            }
+        }
+    }
+
+    pub(crate) fn spawn(self, f: impl Future<Output = ()> + Send + 'static) {
+        match self {
+            #[cfg(feature = "tokio-comp")]
+            Runtime::Tokio => {
+                tokio::spawn(f);
+            }
+            #[cfg(feature = "async-std-comp")]
+            Runtime::AsyncStd => {
+                async_std::task::spawn(f);
+            }
+        }
+    }
+
+    pub(crate) fn sleep(self, duration: Duration) -> BoxFuture<'static, ()> {
+        match self {
+            #[cfg(feature = "tokio-comp")]
+            Runtime::Tokio => Box::pin(tokio::time::sleep(duration)),
+            #[cfg(feature = "async-std-comp")]
+            Runtime::AsyncStd => Box::pin(async_std::task::sleep(duration)),
+        }
+    }
+
+    pub(crate) async fn timeout<F: Future>(
+        self,
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        match self {
+            #[cfg(feature = "tokio-comp")]
+            Runtime::Tokio => tokio::time::timeout(duration, future)
+                .await
+                .map_err(|_| Elapsed),
+            #[cfg(feature = "async-std-comp")]
+            Runtime::AsyncStd => async_std::future::timeout(duration, future)
+                .await
+                .map_err(|_| Elapsed),
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Key iteration over the whole cluster with `SCAN`, and over a single key with `HSCAN`, `SSCAN`
+//! and `ZSCAN`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use futures::{prelude::*, stream};
+use log::{trace, warn};
+use redis::{aio::ConnectionLike, cmd, Cmd, FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::Connection;
+
+// How many times in a row the masters are looked up again after a node failed during a scan
+// before the error is returned
+const MAX_SCAN_RECOVERIES: u32 = 3;
+
+/// The `MATCH` and `COUNT` options of the scan commands.
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    pattern: Option<String>,
+    count: Option<usize>,
+}
+
+impl ScanOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only return the keys (or fields, members) matching the glob-style `pattern`.
+    pub fn with_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    /// Hint how many elements each call should return.
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    fn cmd(&self, name: &str, key: Option<&[u8]>, cursor: u64) -> Cmd {
+        let mut cmd = Cmd::new();
+        cmd.arg(name);
+        if let Some(key) = key {
+            cmd.arg(key);
+        }
+        cmd.arg(cursor);
+        if let Some(pattern) = &self.pattern {
+            cmd.arg("MATCH").arg(pattern);
+        }
+        if let Some(count) = self.count {
+            cmd.arg("COUNT").arg(count);
+        }
+        cmd
+    }
+}
+
+struct ClusterScan<C> {
+    connection: Connection<C>,
+    options: ScanOptions,
+    // Masters which have been scanned completely
+    scanned: HashSet<String>,
+    queue: VecDeque<String>,
+    // The master being scanned and its cursor
+    current: Option<(String, u64)>,
+    keys: VecDeque<Vec<u8>>,
+    recoveries: u32,
+}
+
+impl<C> ClusterScan<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    // Fetch the next batch of keys, returns `Ok(false)` once every master has been scanned
+    async fn fetch(&mut self) -> RedisResult<bool> {
+        let (node, cursor) = match self.current.take() {
+            Some(current) => current,
+            None => {
+                if self.queue.is_empty() {
+                    // Look for masters which joined the cluster (or took over the slots of a
+                    // failed master) since the scan started
+                    self.queue = self
+                        .masters()
+                        .await?
+                        .into_iter()
+                        .filter(|master| !self.scanned.contains(master))
+                        .collect();
+                }
+                match self.queue.pop_front() {
+                    Some(node) => (node, 0),
+                    None => return Ok(false),
+                }
+            }
+        };
+
+        let cmd = self.options.cmd("SCAN", None, cursor);
+        let result = self
+            .connection
+            .send_command(&cmd, Some(node.clone()))
+            .await
+            .and_then(|value| <(u64, Vec<Vec<u8>>)>::from_redis_value(&value));
+        match result {
+            Ok((cursor, keys)) => {
+                self.recoveries = 0;
+                self.keys.extend(keys);
+                if cursor == 0 {
+                    self.scanned.insert(node);
+                } else {
+                    self.current = Some((node, cursor));
+                }
+                Ok(true)
+            }
+            Err(err)
+                if crate::is_retryable_error(&err) && self.recoveries < MAX_SCAN_RECOVERIES =>
+            {
+                // The node may have left the cluster, continue with the masters of the current
+                // topology. If the node is still a master it is scanned again from the start.
+                warn!("Scan of {} failed, refreshing the masters: {}", node, err);
+                self.recoveries += 1;
+                self.queue.clear();
+                Ok(true)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn masters(&mut self) -> RedisResult<Vec<String>> {
+        let slots = crate::get_slots(&mut self.connection).await?;
+        let mut masters = Vec::new();
+        for slot in slots {
+            if !masters.iter().any(|master| master == slot.master()) {
+                masters.push(slot.master().to_string());
+            }
+        }
+        trace!("Scanning masters {:?}", masters);
+        Ok(masters)
+    }
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Iterate over the keys of the whole cluster, running `SCAN` on each master in turn.
+    ///
+    /// As with `SCAN` a key may be returned more than once, in particular when a node fails
+    /// during the scan: its slots are looked up again and the master now serving them is scanned
+    /// from the start.
+    ///
+    /// The keys are returned as they are stored, use `String::from_utf8` on the keys known to be
+    /// text.
+    pub fn scan(&self, options: ScanOptions) -> impl Stream<Item = RedisResult<Vec<u8>>> {
+        let scan = ClusterScan {
+            connection: Connection(self.0.clone()),
+            options,
+            scanned: HashSet::new(),
+            queue: VecDeque::new(),
+            current: None,
+            keys: VecDeque::new(),
+            recoveries: 0,
+        };
+        stream::unfold(Some(scan), |scan| async move {
+            let mut scan = scan?;
+            loop {
+                if let Some(key) = scan.keys.pop_front() {
+                    return Some((Ok(key), Some(scan)));
+                }
+                match scan.fetch().await {
+                    Ok(true) => (),
+                    Ok(false) => return None,
+                    // The scan ends after its first error
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        })
+    }
+
+    /// Delete the keys matching the glob-style `pattern` over the whole cluster and return how
+    /// many were deleted. The keys found by `Connection::scan` are taken `batch_size` at a time
+    /// (also the `COUNT` of the scan) and unlinked with `UNLINK`, one command per slot as mapped
+    /// by `Client::set_hash_key`. The commands of a batch are sent concurrently and each is routed
+    /// to the master owning its keys, so they are pipelined over the connection to each master,
+    /// and each follows its own `MOVED` and `ASK` redirections while slots are being migrated.
+    ///
+    /// Keys written during the deletion may or may not be deleted. The batches deleted so far
+    /// stay deleted when an error is returned.
+    pub async fn scan_del(&self, pattern: &str, batch_size: usize) -> RedisResult<u64> {
+        let batch_size = batch_size.max(1);
+        let options = ScanOptions::new()
+            .with_pattern(pattern)
+            .with_count(batch_size);
+        let mut batches = Box::pin(self.scan(options).chunks(batch_size));
+        let mut deleted = 0;
+        while let Some(batch) = batches.next().await {
+            // The scan ends after its first error, the keys found before it are deleted
+            let mut keys = Vec::with_capacity(batch.len());
+            let mut failure = None;
+            for key in batch {
+                match key {
+                    Ok(key) => keys.push(key),
+                    Err(err) => failure = Some(err),
+                }
+            }
+            deleted += self.unlink_batch(&keys).await?;
+            if let Some(err) = failure {
+                return Err(err);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn unlink_batch(&self, keys: &[Vec<u8>]) -> RedisResult<u64> {
+        // The keys of a slot, which share a hash tag or were returned twice by the scan, are
+        // unlinked together
+        let hasher = self.slot_hasher().await?;
+        let mut groups: HashMap<u16, Vec<&[u8]>> = HashMap::new();
+        for key in keys {
+            groups
+                .entry(hasher.slot_for_key(key))
+                .or_default()
+                .push(key);
+        }
+        let counts = future::try_join_all(groups.into_values().map(|keys| {
+            let mut connection = Connection(self.0.clone());
+            async move {
+                cmd("UNLINK")
+                    .arg(keys)
+                    .query_async::<_, u64>(&mut connection)
+                    .await
+            }
+        }))
+        .await?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Iterate over the fields and values of the hash `key` with `HSCAN`.
+    pub fn hscan<K, T>(&self, key: K, options: ScanOptions) -> impl Stream<Item = RedisResult<T>>
+    where
+        K: ToRedisArgs,
+        T: FromRedisValue + Send + 'static,
+    {
+        self.scan_key("HSCAN", key, options)
+    }
+
+    /// Iterate over the members of the set `key` with `SSCAN`.
+    pub fn sscan<K, T>(&self, key: K, options: ScanOptions) -> impl Stream<Item = RedisResult<T>>
+    where
+        K: ToRedisArgs,
+        T: FromRedisValue + Send + 'static,
+    {
+        self.scan_key("SSCAN", key, options)
+    }
+
+    /// Iterate over the members and scores of the sorted set `key` with `ZSCAN`.
+    pub fn zscan<K, T>(&self, key: K, options: ScanOptions) -> impl Stream<Item = RedisResult<T>>
+    where
+        K: ToRedisArgs,
+        T: FromRedisValue + Send + 'static,
+    {
+        self.scan_key("ZSCAN", key, options)
+    }
+
+    // The elements of a single key all live on the node serving its slot, so the commands are
+    // routed by the key like any other command
+    fn scan_key<K, T>(
+        &self,
+        name: &'static str,
+        key: K,
+        options: ScanOptions,
+    ) -> impl Stream<Item = RedisResult<T>>
+    where
+        K: ToRedisArgs,
+        T: FromRedisValue + Send + 'static,
+    {
+        let scan = KeyScan {
+            connection: Connection(self.0.clone()),
+            options,
+            name,
+            key: key.to_redis_args().concat(),
+            cursor: Some(0),
+            items: VecDeque::new(),
+        };
+        stream::unfold(Some(scan), |scan| async move {
+            let mut scan = scan?;
+            loop {
+                if let Some(item) = scan.items.pop_front() {
+                    return Some((Ok(item), Some(scan)));
+                }
+                match scan.fetch().await {
+                    Ok(true) => (),
+                    Ok(false) => return None,
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        })
+    }
+}
+
+struct KeyScan<C, T> {
+    connection: Connection<C>,
+    options: ScanOptions,
+    name: &'static str,
+    key: Vec<u8>,
+    // `None` once the last batch has been fetched
+    cursor: Option<u64>,
+    items: VecDeque<T>,
+}
+
+impl<C, T> KeyScan<C, T>
+where
+    C: ConnectionLike + Send + 'static,
+    T: FromRedisValue,
+{
+    async fn fetch(&mut self) -> RedisResult<bool> {
+        let cursor = match self.cursor {
+            Some(cursor) => cursor,
+            None => return Ok(false),
+        };
+        let (cursor, items): (u64, Vec<T>) = self
+            .options
+            .cmd(self.name, Some(&self.key), cursor)
+            .query_async(&mut self.connection)
+            .await?;
+        self.items.extend(items);
+        self.cursor = Some(cursor).filter(|cursor| *cursor != 0);
+        Ok(true)
+    }
+}
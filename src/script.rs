@@ -0,0 +1,91 @@
+//! Scripts loaded once on every master and invoked by their SHA1 digest.
+
+use redis::{
+    aio::ConnectionLike, cmd, ErrorKind, FromRedisValue, RedisResult, Script, ToRedisArgs, Value,
+};
+
+use crate::Connection;
+
+/// A script loaded on every master by `Connection::register_script`, invoked with `EVALSHA`.
+pub struct ScriptHandle<C> {
+    connection: Connection<C>,
+    code: String,
+    hash: String,
+}
+
+impl<C> Clone for ScriptHandle<C> {
+    fn clone(&self) -> Self {
+        ScriptHandle {
+            connection: Connection(self.connection.0.clone()),
+            code: self.code.clone(),
+            hash: self.hash.clone(),
+        }
+    }
+}
+
+impl<C> ScriptHandle<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// The SHA1 digest of the script.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Run the script with `EVALSHA`, routed by `keys`, which must be in the same slot. A master
+    /// which does not know the script, such as one added to the cluster after the script was
+    /// registered, runs it with `EVAL` instead, which caches it there for the next calls. If the
+    /// script cache was flushed through the connection since, the script is registered again
+    /// before being retried.
+    pub async fn invoke_async<K, A, T>(&self, keys: K, args: A) -> RedisResult<T>
+    where
+        K: ToRedisArgs,
+        A: ToRedisArgs,
+        T: FromRedisValue,
+    {
+        let keys = keys.to_redis_args();
+        let mut evalsha = cmd("EVALSHA");
+        evalsha.arg(&self.hash).arg(keys.len()).arg(keys).arg(args);
+        let mut connection = Connection(self.connection.0.clone());
+        match evalsha.query_async(&mut connection).await {
+            Err(err) if err.kind() == ErrorKind::NoScriptError => {
+                load_script(&mut connection, &self.code).await?;
+                evalsha.query_async(&mut connection).await
+            }
+            result => result,
+        }
+    }
+}
+
+// `SCRIPT LOAD` is sent to every master, and the connection remembers the source of the script
+// to run it on the masters which do not have it
+async fn load_script<C>(connection: &mut Connection<C>, code: &str) -> RedisResult<()>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    cmd("SCRIPT")
+        .arg("LOAD")
+        .arg(code)
+        .query_async::<_, Value>(connection)
+        .await?;
+    Ok(())
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Load the script of source `code` on every master with `SCRIPT LOAD` and return a handle
+    /// invoking it by its digest, without the `NOSCRIPT` round trip `Script::invoke_async` makes
+    /// the first time it runs on each master. The source is taken as a string since
+    /// `redis::Script` does not give it back.
+    pub async fn register_script(&self, code: &str) -> RedisResult<ScriptHandle<C>> {
+        let mut connection = Connection(self.0.clone());
+        load_script(&mut connection, code).await?;
+        Ok(ScriptHandle {
+            connection,
+            code: code.to_string(),
+            hash: Script::new(code).get_hash().to_string(),
+        })
+    }
+}
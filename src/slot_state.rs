@@ -0,0 +1,99 @@
+//! Telling whether a slot is being migrated between masters, from the `CLUSTER NODES` reply of
+//! each master.
+
+use std::time::{Duration, Instant};
+
+use redis::{aio::ConnectionLike, cmd, ErrorKind, FromRedisValue, RedisError, RedisResult};
+
+use crate::{runtime::Runtime, Connection};
+
+// How often `Connection::wait_slot_stable` checks the state of the slot
+const SLOT_STATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether a slot is being migrated, see `Connection::slot_state`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlotState {
+    /// No master migrates or imports the slot.
+    #[default]
+    Stable,
+    /// The master serving the slot is migrating it to another master (`CLUSTER SETSLOT ...
+    /// MIGRATING`).
+    Migrating,
+    /// A master is importing the slot (`CLUSTER SETSLOT ... IMPORTING`) while the master serving
+    /// it is not migrating it, e.g. before the migration started or once it was done.
+    Importing,
+}
+
+// The state of `slot` as reported by a node in its own line of `CLUSTER NODES`, e.g.
+// `[93->-<node id>]` while migrating and `[93-<-<node id>]` while importing
+fn parse_slot_state(nodes: &str, slot: u16) -> SlotState {
+    let myself = nodes.lines().find(|line| {
+        let flags = line.split(' ').nth(2).unwrap_or_default();
+        flags.split(',').any(|flag| flag == "myself")
+    });
+    let slots = myself.into_iter().flat_map(|line| line.split(' ').skip(8));
+    for entry in slots {
+        let entry = match entry.strip_prefix('[') {
+            Some(entry) => entry.trim_end_matches(']'),
+            None => continue,
+        };
+        let (entry_slot, state) = if let Some((entry_slot, _)) = entry.split_once("->-") {
+            (entry_slot, SlotState::Migrating)
+        } else if let Some((entry_slot, _)) = entry.split_once("-<-") {
+            (entry_slot, SlotState::Importing)
+        } else {
+            continue;
+        };
+        if entry_slot.parse() == Ok(slot) {
+            return state;
+        }
+    }
+    SlotState::Stable
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Whether `slot` is being migrated, asking every master with `CLUSTER NODES` since a node
+    /// only reports the slots it migrates or imports itself. Fails if any of the masters fails.
+    pub async fn slot_state(&mut self, slot: u16) -> RedisResult<SlotState> {
+        let mut importing = false;
+        for (_, result) in self.broadcast(cmd("CLUSTER").arg("NODES")).await? {
+            match parse_slot_state(&String::from_redis_value(&result?)?, slot) {
+                SlotState::Migrating => return Ok(SlotState::Migrating),
+                SlotState::Importing => importing = true,
+                SlotState::Stable => (),
+            }
+        }
+        Ok(if importing {
+            SlotState::Importing
+        } else {
+            SlotState::Stable
+        })
+    }
+
+    /// Wait for `slot` to be `SlotState::Stable`, checking it with `Connection::slot_state` every
+    /// 100 milliseconds. Fails with a `TryAgain` error if the slot is still being migrated after
+    /// `timeout`.
+    pub async fn wait_slot_stable(&mut self, slot: u16, timeout: Duration) -> RedisResult<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let state = self.slot_state(slot).await?;
+            if state == SlotState::Stable {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RedisError::from((
+                    ErrorKind::TryAgain,
+                    "Slot is still being migrated",
+                    format!("slot {} is {:?}", slot, state),
+                )));
+            }
+            Runtime::locate()
+                .sleep(SLOT_STATE_POLL_INTERVAL.min(deadline - now))
+                .await;
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! `tracing` instrumentation of the commands, enabled with the `tracing` feature. Each command
+//! sent through a connection gets a debug level `redis_cluster.command` span, a child of the span
+//! it was sent from, recording the slot and the node it was routed to along with the number of
+//! retries and whether it was redirected. Redirections, retries and reconnections are reported as
+//! events of the span. Without the feature the spans are empty and these functions do nothing.
+
+use redis::RedisError;
+
+use crate::Redirect;
+
+#[derive(Clone, Debug)]
+pub(crate) struct CommandSpan(#[cfg(feature = "tracing")] tracing::Span);
+
+impl CommandSpan {
+    // The span of a command (`command` being its name) or pipeline sent by the caller
+    pub(crate) fn new(command: &[u8]) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            CommandSpan(tracing::debug_span!(
+                "redis_cluster.command",
+                command = %String::from_utf8_lossy(command),
+                slot = tracing::field::Empty,
+                node = tracing::field::Empty,
+                retries = 0u32,
+                redirected = false,
+            ))
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = command;
+            CommandSpan()
+        }
+    }
+
+    // The span of one of the parts a command or pipeline is split into, one per node
+    pub(crate) fn part(&self) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            CommandSpan(tracing::debug_span!(
+                parent: &self.0,
+                "redis_cluster.part",
+                slot = tracing::field::Empty,
+                node = tracing::field::Empty,
+                retries = 0u32,
+                redirected = false,
+            ))
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            CommandSpan()
+        }
+    }
+
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        {
+            self.0.in_scope(f)
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            f()
+        }
+    }
+
+    pub(crate) fn routed(&self, slot: Option<u16>, node: &str) {
+        #[cfg(feature = "tracing")]
+        {
+            if let Some(slot) = slot {
+                self.0.record("slot", &slot);
+            }
+            self.0.record("node", &node);
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (slot, node);
+        }
+    }
+
+    pub(crate) fn retry(&self, retry: u32, node: &str, err: &RedisError) {
+        #[cfg(feature = "tracing")]
+        {
+            self.0.record("retries", &retry);
+            tracing::debug!(parent: &self.0, retry, node, error = %err, "retry");
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (retry, node, err);
+        }
+    }
+
+    pub(crate) fn redirect(&self, redirect: &Redirect) {
+        #[cfg(feature = "tracing")]
+        {
+            self.0.record("redirected", &true);
+            let (slot, node) = (redirect.slot(), redirect.addr());
+            match redirect.kind() {
+                crate::RedirectKind::Moved => tracing::debug!(parent: &self.0, slot, node, "MOVED"),
+                crate::RedirectKind::Ask => tracing::debug!(parent: &self.0, slot, node, "ASK"),
+            }
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = redirect;
+        }
+    }
+}
+
+// A connection to `node` replaces its broken ones, reported in the span of the request
+pub(crate) fn reconnect(node: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(node, "reconnect");
+
+    #[cfg(not(feature = "tracing"))]
+    let _ = node;
+}
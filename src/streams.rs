@@ -0,0 +1,294 @@
+//! Consuming streams as a consumer group member with blocking `XREADGROUP` calls, each stream
+//! being read over a dedicated connection to the master serving it.
+
+use std::time::Duration;
+
+use futures::{prelude::*, stream};
+use log::{trace, warn};
+use redis::{aio::ConnectionLike, cmd, ErrorKind, RedisError, RedisResult, Value};
+
+use crate::{runtime::Runtime, Connection, DedicatedConnection, RetryPolicy};
+
+// How many times in a row the connection to the master of a stream is opened again after a
+// failure before the error is returned
+const MAX_STREAM_RECOVERIES: u32 = 16;
+
+const RECOVERY_POLICY: RetryPolicy = RetryPolicy::ExponentialBackoff {
+    min: Duration::from_millis(100),
+    max: Duration::from_secs(5),
+};
+
+/// The options of `Connection::xread_group_stream`.
+#[derive(Clone, Debug)]
+pub struct StreamReadOptions {
+    count: Option<usize>,
+    block: Duration,
+    noack: bool,
+}
+
+impl Default for StreamReadOptions {
+    fn default() -> Self {
+        StreamReadOptions {
+            count: None,
+            block: Duration::from_secs(5),
+            noack: false,
+        }
+    }
+}
+
+impl StreamReadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read at most `count` entries of each stream per call (`COUNT`).
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// How long each call waits for new entries (`BLOCK`) before the next one is made. Failures
+    /// of the node are noticed once the call fails, keep it short enough for the calls to time
+    /// out regularly. Default: 5 seconds
+    pub fn with_block(mut self, block: Duration) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Do not add the entries to the pending entries list of the group (`NOACK`), they need no
+    /// `XACK`.
+    pub fn with_noack(mut self) -> Self {
+        self.noack = true;
+        self
+    }
+}
+
+/// An entry of a stream read by `Connection::xread_group_stream`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamEntry {
+    key: Vec<u8>,
+    id: String,
+    fields: Vec<(String, Value)>,
+}
+
+impl StreamEntry {
+    /// The key of the stream.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The ID of the entry.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The fields and values of the entry, in order. Empty for a pending entry which was deleted
+    /// from the stream.
+    pub fn fields(&self) -> &[(String, Value)] {
+        &self.fields
+    }
+}
+
+struct GroupReader<C> {
+    connection: Connection<C>,
+    dedicated: Option<DedicatedConnection<C>>,
+    group: String,
+    consumer: String,
+    key: Vec<u8>,
+    options: StreamReadOptions,
+    // `>` for the entries never delivered, or the ID of the last entry delivered before the
+    // connection failed (`0` if there was none) to fetch the entries delivered to the consumer
+    // since
+    id: String,
+    last_delivered: Option<String>,
+    recoveries: u32,
+}
+
+impl<C> GroupReader<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    async fn fetch(&mut self) -> RedisResult<Vec<StreamEntry>> {
+        let dedicated = match &mut self.dedicated {
+            Some(dedicated) => dedicated,
+            None => {
+                let dedicated = self.connection.take_dedicated(&self.key[..]).await?;
+                self.dedicated.get_or_insert(dedicated)
+            }
+        };
+
+        let mut read = cmd("XREADGROUP");
+        read.arg("GROUP").arg(&self.group).arg(&self.consumer);
+        if let Some(count) = self.options.count {
+            read.arg("COUNT").arg(count);
+        }
+        // Entries which were already delivered are returned without blocking
+        if self.id == ">" {
+            read.arg("BLOCK").arg(self.options.block.as_millis() as u64);
+        }
+        if self.options.noack {
+            read.arg("NOACK");
+        }
+        read.arg("STREAMS").arg(&self.key).arg(&self.id);
+        let entries = read
+            .query_async(dedicated)
+            .await
+            .and_then(|reply| parse_entries(&reply))?;
+
+        if self.id != ">" && entries.is_empty() {
+            trace!(
+                "Caught up with the entries of {}",
+                String::from_utf8_lossy(&self.key)
+            );
+            self.id = ">".into();
+        }
+        if let Some(entry) = entries.last() {
+            if self.id != ">" {
+                self.id = entry.id.clone();
+            }
+            self.last_delivered = Some(entry.id.clone());
+        }
+        Ok(entries)
+    }
+
+    // Prepare to read again after `err`, `false` if the error is returned instead
+    async fn recover(&mut self, err: &RedisError) -> bool {
+        if !crate::is_retryable_error(err) || self.recoveries >= MAX_STREAM_RECOVERIES {
+            return false;
+        }
+        self.recoveries += 1;
+        let key = String::from_utf8_lossy(&self.key);
+        warn!("Reading stream {} failed, reconnecting: {}", key, err);
+        // The dedicated connection keeps talking to the same node, send a command routed by the
+        // key so the connection follows a failover before the next dedicated connection is taken.
+        // It creates the consumer which reading would create anyway, its result does not matter.
+        self.dedicated = None;
+        let _ = cmd("XGROUP")
+            .arg("CREATECONSUMER")
+            .arg(&self.key)
+            .arg(&self.group)
+            .arg(&self.consumer)
+            .query_async::<_, Value>(&mut self.connection)
+            .await;
+        Runtime::locate()
+            .sleep(RECOVERY_POLICY.delay(self.recoveries))
+            .await;
+        // Resume with the entries delivered to the consumer since the last one received. When
+        // none was received the failed read may still have delivered some, they are in the
+        // pending entries list of the consumer unless `NOACK` is set.
+        match &self.last_delivered {
+            Some(last_delivered) => self.id = last_delivered.clone(),
+            None if !self.options.noack => self.id = "0".into(),
+            None => (),
+        }
+        true
+    }
+}
+
+// Parse the reply of `XREADGROUP` for a single stream, nil once `BLOCK` timed out
+fn parse_entries(reply: &Value) -> RedisResult<Vec<StreamEntry>> {
+    let unexpected = || RedisError::from((ErrorKind::TypeError, "Unexpected XREADGROUP reply"));
+    let streams = match reply {
+        Value::Nil => return Ok(Vec::new()),
+        Value::Bulk(streams) => streams,
+        _ => return Err(unexpected()),
+    };
+    let mut entries = Vec::new();
+    for stream in streams {
+        let (key, stream_entries) = match stream {
+            Value::Bulk(stream) => match &stream[..] {
+                [Value::Data(key), Value::Bulk(entries)] => (key, entries),
+                _ => return Err(unexpected()),
+            },
+            _ => return Err(unexpected()),
+        };
+        for entry in stream_entries {
+            let (id, fields) = match entry {
+                Value::Bulk(entry) => match &entry[..] {
+                    [Value::Data(id), Value::Bulk(fields)] => (id, &fields[..]),
+                    [Value::Data(id), Value::Nil] => (id, &[][..]),
+                    _ => return Err(unexpected()),
+                },
+                _ => return Err(unexpected()),
+            };
+            let fields = fields
+                .chunks(2)
+                .map(|field| match field {
+                    [Value::Data(name), value] => {
+                        Ok((String::from_utf8_lossy(name).into_owned(), value.clone()))
+                    }
+                    _ => Err(unexpected()),
+                })
+                .collect::<RedisResult<_>>()?;
+            entries.push(StreamEntry {
+                key: key.clone(),
+                id: String::from_utf8_lossy(id).into_owned(),
+                fields,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+impl<C> Connection<C>
+where
+    C: ConnectionLike + Send + 'static,
+{
+    /// Read the new entries of `streams` as the `consumer` of the consumer `group` with
+    /// `XREADGROUP ... BLOCK`, the entries of all the streams being returned as they arrive. Each
+    /// stream is read over a dedicated connection (see `Connection::take_dedicated`) to the
+    /// master serving it, so the blocking calls do not hold up the other requests.
+    ///
+    /// When reading a stream fails with a retryable error, e.g. because its master failed over,
+    /// a new dedicated connection is taken to the master now serving it and the entries delivered
+    /// to the consumer since the last one returned (all its pending entries if none was returned
+    /// yet) are read again before the new ones. After
+    /// repeated failures, or a failure which is not retried such as a missing group (`NOGROUP`),
+    /// the error is returned and that stream is no longer read, the other streams go on. The
+    /// entries still have to be acknowledged with `XACK` unless `StreamReadOptions::with_noack`
+    /// is set.
+    pub fn xread_group_stream<K: AsRef<[u8]>>(
+        &self,
+        group: &str,
+        consumer: &str,
+        streams: &[K],
+        options: StreamReadOptions,
+    ) -> impl Stream<Item = RedisResult<StreamEntry>> {
+        let readers = streams.iter().map(|key| {
+            let reader = GroupReader {
+                connection: Connection(self.0.clone()),
+                dedicated: None,
+                group: group.to_string(),
+                consumer: consumer.to_string(),
+                key: key.as_ref().to_vec(),
+                options: options.clone(),
+                id: ">".into(),
+                last_delivered: None,
+                recoveries: 0,
+            };
+            stream::unfold(Some(reader), |reader| async move {
+                let mut reader = reader?;
+                loop {
+                    match reader.fetch().await {
+                        Ok(entries) => {
+                            reader.recoveries = 0;
+                            if !entries.is_empty() {
+                                let entries = stream::iter(entries.into_iter().map(Ok));
+                                return Some((entries.left_stream(), Some(reader)));
+                            }
+                        }
+                        Err(err) => {
+                            if !reader.recover(&err).await {
+                                let err = stream::once(future::ready(Err(err)));
+                                return Some((err.right_stream(), None));
+                            }
+                        }
+                    }
+                }
+            })
+            .flatten()
+            .boxed()
+        });
+        stream::select_all(readers)
+    }
+}
@@ -0,0 +1,81 @@
+//! Opening the TCP connections to the nodes with the socket settings of the client
+//! (`Client::set_tcp_keepalive` and `Client::set_tcp_nodelay`).
+
+use std::io;
+
+use redis::{aio::MultiplexedConnection, RedisConnectionInfo, RedisResult};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{runtime::Runtime, SocketOptions};
+
+#[cfg(feature = "tokio-comp")]
+pub(crate) async fn connect_tokio(
+    host: &str,
+    port: u16,
+    options: &SocketOptions,
+) -> io::Result<tokio::net::TcpStream> {
+    let stream = tokio::net::TcpStream::connect((host, port)).await?;
+    configure(&stream, options)?;
+    Ok(stream)
+}
+
+#[cfg(feature = "async-std-comp")]
+pub(crate) async fn connect_async_std(
+    host: &str,
+    port: u16,
+    options: &SocketOptions,
+) -> io::Result<async_std::net::TcpStream> {
+    let stream = async_std::net::TcpStream::connect((host, port)).await?;
+    configure(&stream, options)?;
+    Ok(stream)
+}
+
+// A multiplexed connection over TCP, authenticated as `redis_info` says
+pub(crate) async fn connect(
+    host: &str,
+    port: u16,
+    redis_info: &RedisConnectionInfo,
+    options: &SocketOptions,
+) -> RedisResult<MultiplexedConnection> {
+    match Runtime::locate() {
+        #[cfg(feature = "tokio-comp")]
+        Runtime::Tokio => {
+            let stream = connect_tokio(host, port, options).await?;
+            multiplexed(stream, redis_info).await
+        }
+        #[cfg(feature = "async-std-comp")]
+        Runtime::AsyncStd => {
+            use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+            let stream = connect_async_std(host, port, options).await?;
+            multiplexed(stream.compat(), redis_info).await
+        }
+    }
+}
+
+async fn multiplexed<S>(
+    stream: S,
+    redis_info: &RedisConnectionInfo,
+) -> RedisResult<MultiplexedConnection>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (connection, driver) = MultiplexedConnection::new(redis_info, stream).await?;
+    Runtime::locate().spawn(driver);
+    Ok(connection)
+}
+
+fn configure<S>(stream: &S, options: &SocketOptions) -> io::Result<()>
+where
+    for<'s> SockRef<'s>: From<&'s S>,
+{
+    let socket = SockRef::from(stream);
+    if options.tcp_nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(time) = options.tcp_keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+//! TLS support for the node connections, implemented with rustls.
+
+use std::{convert::TryFrom, sync::Arc};
+
+use redis::{aio::MultiplexedConnection, ErrorKind, RedisConnectionInfo, RedisError, RedisResult};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::SocketOptions;
+
+/// TLS settings shared by every connection to the nodes of the cluster.
+///
+/// By default the server certificates are verified against the Mozilla root certificates
+/// (`webpki-roots`), additional roots such as a private CA can be added with
+/// `add_root_certificate_pem`.
+#[derive(Clone)]
+pub struct ClientTlsConfig {
+    root_certificates: Vec<Vec<u8>>,
+    client_certificate: Option<(Vec<Vec<u8>>, Vec<u8>)>,
+    // Built again whenever the certificates change, then shared by every connection
+    config: Arc<ClientConfig>,
+}
+
+impl Default for ClientTlsConfig {
+    fn default() -> Self {
+        let config = client_config(&[], None).expect("The default TLS configuration is valid");
+        ClientTlsConfig {
+            root_certificates: Vec::new(),
+            client_certificate: None,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl ClientTlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the certificates of a PEM encoded CA bundle in addition to the default roots.
+    pub fn add_root_certificate_pem(&mut self, pem: &[u8]) -> RedisResult<&mut Self> {
+        let certs = rustls_pemfile::certs(&mut &pem[..])?;
+        if certs.is_empty() {
+            return Err(invalid_config(
+                "No certificate found in the root certificate PEM",
+            ));
+        }
+        let mut root_certificates = self.root_certificates.clone();
+        root_certificates.extend(certs);
+        self.config = Arc::new(client_config(
+            &root_certificates,
+            self.client_certificate.as_ref(),
+        )?);
+        self.root_certificates = root_certificates;
+        Ok(self)
+    }
+
+    /// Authenticate with a client certificate (mutual TLS). `cert_chain` and `key` are PEM encoded,
+    /// the key may be a PKCS#8, RSA or EC key.
+    pub fn set_client_certificate_pem(
+        &mut self,
+        cert_chain: &[u8],
+        key: &[u8],
+    ) -> RedisResult<&mut Self> {
+        let certs = rustls_pemfile::certs(&mut &cert_chain[..])?;
+        if certs.is_empty() {
+            return Err(invalid_config(
+                "No certificate found in the client certificate PEM",
+            ));
+        }
+        let key = rustls_pemfile::read_all(&mut &key[..])?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| invalid_config("No private key found in the client key PEM"))?;
+        let client_certificate = (certs, key);
+        self.config = Arc::new(client_config(
+            &self.root_certificates,
+            Some(&client_certificate),
+        )?);
+        self.client_certificate = Some(client_certificate);
+        Ok(self)
+    }
+
+    pub(crate) fn shared_config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+}
+
+fn client_config(
+    root_certificates: &[Vec<u8>],
+    client_certificate: Option<&(Vec<Vec<u8>>, Vec<u8>)>,
+) -> RedisResult<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    for cert in root_certificates {
+        roots
+            .add(&Certificate(cert.clone()))
+            .map_err(|err| invalid_config_detail("Invalid root certificate", err))?;
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    match client_certificate {
+        Some((certs, key)) => builder
+            .with_single_cert(
+                certs.iter().cloned().map(Certificate).collect(),
+                PrivateKey(key.clone()),
+            )
+            .map_err(|err| invalid_config_detail("Invalid client certificate", err)),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+pub(crate) async fn connect(
+    host: &str,
+    port: u16,
+    insecure: bool,
+    options: &SocketOptions,
+    redis_info: &RedisConnectionInfo,
+) -> RedisResult<MultiplexedConnection> {
+    let stream = connect_stream(host, port, insecure, options).await?;
+    let (connection, driver) = MultiplexedConnection::new(redis_info, stream).await?;
+    tokio::spawn(driver);
+    Ok(connection)
+}
+
+pub(crate) async fn connect_stream(
+    host: &str,
+    port: u16,
+    insecure: bool,
+    options: &SocketOptions,
+) -> RedisResult<TlsStream<TcpStream>> {
+    if insecure {
+        return Err(invalid_config(
+            "Insecure TLS connections are not supported, add the CA of the cluster instead",
+        ));
+    }
+    let server_name = ServerName::try_from(host)
+        .map_err(|err| invalid_config_detail("Invalid TLS server name", err))?;
+    let config = match options.tls_config() {
+        Some(config) => config.clone(),
+        None => ClientTlsConfig::default().shared_config(),
+    };
+    let connector = TlsConnector::from(config);
+
+    let stream = crate::tcp::connect_tokio(host, port, options).await?;
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+fn invalid_config(description: &'static str) -> RedisError {
+    RedisError::from((ErrorKind::InvalidClientConfig, description))
+}
+
+fn invalid_config_detail(description: &'static str, err: impl std::fmt::Display) -> RedisError {
+    RedisError::from((ErrorKind::InvalidClientConfig, description, err.to_string()))
+}
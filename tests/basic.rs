@@ -18,7 +18,7 @@ use redis_cluster_async::{
 const REDIS_URL: &str = "redis://127.0.0.1:7000/";
 
 pub struct RedisProcess;
-pub struct RedisLock(MutexGuard<'static, RedisProcess>);
+pub struct RedisLock(#[allow(dead_code)] MutexGuard<'static, RedisProcess>);
 
 impl RedisProcess {
     // Blocks until we have sole access.
@@ -42,10 +42,15 @@ pub struct RuntimeEnv {
     pub runtime: Runtime,
 }
 
+impl Default for RuntimeEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RuntimeEnv {
     pub fn new() -> Self {
-        let mut runtime = tokio::runtime::Builder::new()
-            .basic_scheduler()
+        let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_io()
             .enable_time()
             .build()
@@ -76,7 +81,7 @@ impl RedisEnv {
 
         'outer: loop {
             let node_infos = async {
-                let mut conn = redis_client.get_multiplexed_tokio_connection().await?;
+                let mut conn = redis_client.get_multiplexed_async_connection().await?;
                 Self::cluster_info(&mut conn).await
             }
             .await
@@ -90,7 +95,7 @@ impl RedisEnv {
                     for (url, master) in node_infos {
                         let redis_client = redis::Client::open(&url[..])
                             .unwrap_or_else(|_| panic!("Failed to connect to '{}'", url));
-                        let mut conn = redis_client.get_multiplexed_tokio_connection().await?;
+                        let mut conn = redis_client.get_multiplexed_async_connection().await?;
 
                         if master {
                             master_urls.push(url.to_string());
@@ -118,7 +123,7 @@ impl RedisEnv {
                     }
                 }
             }
-            tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
 
         let client = Client::open(master_urls.iter().map(|s| &s[..]).collect()).unwrap();
@@ -145,12 +150,12 @@ impl RedisEnv {
                             .by_ref()
                             .nth(1)
                             .expect("Node ip")
-                            .splitn(2, '@')
+                            .split('@')
                             .next()
                             .unwrap()
-                            .splitn(2, ':')
-                            .nth(1)
-                            .unwrap();
+                            .split_once(':')
+                            .unwrap()
+                            .1;
                         (
                             format!("redis://localhost:{}", port),
                             iter.next().expect("master").contains("master"),
@@ -207,7 +212,6 @@ async fn basic_eval() {
     .unwrap()
 }
 
-#[ignore] // TODO Handle running SCRIPT LOAD on all masters
 #[tokio::test]
 async fn basic_script() {
     let env = RedisEnv::new().await;
@@ -229,7 +233,6 @@ async fn basic_script() {
     .unwrap()
 }
 
-#[ignore] // TODO Handle pipe where the keys do not all go to the same node
 #[tokio::test]
 async fn basic_pipe() {
     let env = RedisEnv::new().await;
@@ -251,13 +254,80 @@ async fn basic_pipe() {
     .unwrap()
 }
 
+#[tokio::test]
+async fn basic_pubsub() {
+    let env = RedisEnv::new().await;
+    let client = env.client;
+    async {
+        let mut connection = client.get_connection().await?;
+        let mut pubsub = client.get_pubsub().await?;
+        pubsub.subscribe("test-channel").await?;
+        // Wait for the subscription to be processed by the node
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let _: i64 = cmd("PUBLISH")
+            .arg("test-channel")
+            .arg("test_data")
+            .query_async(&mut connection)
+            .await?;
+        let msg = pubsub.next().await.expect("message");
+        assert_eq!(msg.get_channel_name(), "test-channel");
+        assert_eq!(msg.get_payload::<String>()?, "test_data");
+        Ok(())
+    }
+    .await
+    .map_err(|err: RedisError| err)
+    .unwrap()
+}
+
+#[tokio::test]
+async fn basic_keyevents() {
+    let env = RedisEnv::new().await;
+    let client = env.client;
+    async {
+        let mut connection = client.get_connection().await?;
+        for (_, result) in connection
+            .broadcast(
+                cmd("CONFIG")
+                    .arg("SET")
+                    .arg("notify-keyspace-events")
+                    .arg("Ex"),
+            )
+            .await?
+        {
+            result?;
+        }
+        let mut events = client.get_keyevents(0, &["expired"]).await?;
+        for key in &["test-expired-1", "test-expired-2", "test-expired-3"] {
+            let () = cmd("SET")
+                .arg(key)
+                .arg("test_data")
+                .arg("PX")
+                .arg(10)
+                .query_async(&mut connection)
+                .await?;
+        }
+        let mut expired = Vec::new();
+        for _ in 0..3 {
+            let event = events.next().await.expect("event");
+            assert_eq!(event.event(), "expired");
+            expired.push(String::from_utf8(event.key().to_vec()).unwrap());
+        }
+        expired.sort();
+        assert_eq!(expired, ["test-expired-1", "test-expired-2", "test-expired-3"]);
+        Ok(())
+    }
+    .await
+    .map_err(|err: RedisError| err)
+    .unwrap()
+}
+
 #[test]
 fn proptests() {
     let env = std::cell::RefCell::new(FailoverEnv::new());
 
     proptest!(
         proptest::prelude::ProptestConfig { cases: 30, failure_persistence: None, .. Default::default() },
-        |(requests in 0..15, value in 0..i32::max_value())| {
+        |(requests in 0..15, value in 0..i32::MAX)| {
             test_failover(&mut env.borrow_mut(), requests, value)
         }
     );
@@ -275,7 +345,7 @@ struct FailoverEnv {
 
 impl FailoverEnv {
     fn new() -> Self {
-        let mut env = RuntimeEnv::new();
+        let env = RuntimeEnv::new();
         let connection = env
             .runtime
             .block_on(env.redis.client.get_connection())
@@ -311,13 +381,13 @@ fn test_failover(env: &mut FailoverEnv, requests: i32, value: i32) {
                 async move {
                     if i == requests / 2 {
                         // Failover all the nodes, error only if all the failover requests error
-                        nodes.iter_mut().map(|node| do_failover(node))
+                        nodes.iter_mut().map(do_failover)
                             .collect::<stream::FuturesUnordered<_>>()
                             .fold(
                                 Err(Box::<dyn Error + Send + Sync>::from("None".to_string())),
                                 |acc: Result<(), Box<dyn Error + Send + Sync>>,
                                  result: Result<(), Box<dyn Error + Send + Sync>>| async move {
-                                    acc.or_else(|_| result)
+                                    acc.or(result)
                                 },
                             )
                             .await
@@ -341,7 +411,7 @@ fn test_failover(env: &mut FailoverEnv, requests: i32, value: i32) {
                 }
             })
             .collect::<stream::FuturesUnordered<_>>()
-            .try_collect()
+            .try_collect::<()>()
             .await
     };
     env.runtime
@@ -1,20 +1,30 @@
 use std::{
-    collections::HashMap,
-    sync::{atomic, Arc, RwLock},
+    collections::{HashMap, HashSet},
+    io,
+    ops::RangeInclusive,
+    sync::{atomic, Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
 use {
-    futures::future,
+    futures::{future, StreamExt},
     redis_cluster_async::{
         redis::{
             aio::ConnectionLike, cmd, parse_redis_value, IntoConnectionInfo, RedisFuture,
-            RedisResult, Value,
+            RedisResult, Script, Value,
         },
-        Client, Connect,
+        Client, ClientFlags, ClusterMetrics, Connect, ConnectConfig, NodeAddress, QueueFullMode,
+        ReadPreference, RedirectKind, RestoreOptions, RetryDecision, RetryPolicy, ScanOptions,
+        SeedStrategy, SlotState, StreamReadOptions,
     },
     tokio::runtime::Runtime,
 };
 
+// Handlers respond with this status to simulate a node which never answers
+const STALL: &str = "MOCK_STALL";
+// Handlers respond with this status to simulate a node answering `OK` after 50 milliseconds
+const SLOW: &str = "MOCK_SLOW";
+
 type Handler = Arc<dyn Fn(&redis::Cmd, u16) -> Result<(), RedisResult<Value>> + Send + Sync>;
 
 lazy_static::lazy_static! {
@@ -35,7 +45,7 @@ impl Connect for MockConnection {
     {
         let info = info.into_connection_info().unwrap();
 
-        let (name, port) = match &*info.addr {
+        let (name, port) = match &info.addr {
             redis::ConnectionAddr::Tcp(addr, port) => (addr, *port),
             _ => unreachable!(),
         };
@@ -61,9 +71,55 @@ fn contains_slice(xs: &[u8], ys: &[u8]) -> bool {
 }
 
 fn respond_startup(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
-    if contains_slice(&cmd, b"PING") {
+    if contains_slice(cmd, b"PING") {
+        Err(Ok(Value::Status("OK".into())))
+    } else if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+        Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+            Value::Int(0),
+            Value::Int(16383),
+            Value::Bulk(vec![
+                Value::Data(name.as_bytes().to_vec()),
+                Value::Int(6379),
+            ]),
+        ])])))
+    } else {
+        Ok(())
+    }
+}
+
+// Two masters on ports 6379 and 6380 which split the slots in half
+fn respond_startup_two_nodes(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
+    if contains_slice(cmd, b"PING") {
+        Err(Ok(Value::Status("OK".into())))
+    } else if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+        Err(Ok(Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(8191),
+                Value::Bulk(vec![
+                    Value::Data(name.as_bytes().to_vec()),
+                    Value::Int(6379),
+                ]),
+            ]),
+            Value::Bulk(vec![
+                Value::Int(8192),
+                Value::Int(16383),
+                Value::Bulk(vec![
+                    Value::Data(name.as_bytes().to_vec()),
+                    Value::Int(6380),
+                ]),
+            ]),
+        ])))
+    } else {
+        Ok(())
+    }
+}
+
+// A master on port 6379 serving every slot, with a replica on port 6380
+fn respond_startup_with_replica(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
+    if contains_slice(cmd, b"PING") || contains_slice(cmd, b"READONLY") {
         Err(Ok(Value::Status("OK".into())))
-    } else if contains_slice(&cmd, b"CLUSTER") && contains_slice(&cmd, b"SLOTS") {
+    } else if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
         Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
             Value::Int(0),
             Value::Int(16383),
@@ -71,6 +127,10 @@ fn respond_startup(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
                 Value::Data(name.as_bytes().to_vec()),
                 Value::Int(6379),
             ]),
+            Value::Bulk(vec![
+                Value::Data(name.as_bytes().to_vec()),
+                Value::Int(6380),
+            ]),
         ])])))
     } else {
         Ok(())
@@ -79,18 +139,43 @@ fn respond_startup(name: &str, cmd: &[u8]) -> Result<(), RedisResult<Value>> {
 
 impl ConnectionLike for MockConnection {
     fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
-        Box::pin(future::ready(
-            (self.handler)(&cmd, self.port).expect_err("Handler did not specify a response"),
-        ))
+        match (self.handler)(cmd, self.port).expect_err("Handler did not specify a response") {
+            Ok(Value::Status(status)) if status == STALL => Box::pin(future::pending()),
+            Ok(Value::Status(status)) if status == SLOW => Box::pin(async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Value::Okay)
+            }),
+            response => Box::pin(future::ready(response)),
+        }
     }
 
     fn req_packed_commands<'a>(
         &'a mut self,
-        _pipeline: &'a redis::Pipeline,
-        _offset: usize,
-        _count: usize,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
     ) -> RedisFuture<'a, Vec<Value>> {
-        Box::pin(future::ok(vec![]))
+        // Transactions skip the responses to `MULTI` and the queued commands and only return the
+        // response to `EXEC`, which holds the results of the commands
+        if offset == pipeline.cmd_iter().count() + 1 {
+            let results = pipeline
+                .cmd_iter()
+                .map(|cmd| {
+                    (self.handler)(cmd, self.port).expect_err("Handler did not specify a response")
+                })
+                .collect::<RedisResult<Vec<_>>>()
+                .map(|values| vec![Value::Bulk(values)]);
+            return Box::pin(future::ready(results));
+        }
+        let results = pipeline
+            .cmd_iter()
+            .map(|cmd| {
+                (self.handler)(cmd, self.port).expect_err("Handler did not specify a response")
+            })
+            .skip(offset)
+            .take(count)
+            .collect();
+        Box::pin(future::ready(results))
     }
 
     fn get_db(&self) -> i64 {
@@ -119,8 +204,7 @@ impl MockEnv {
         id: &str,
         handler: impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static,
     ) -> Self {
-        let mut runtime = tokio::runtime::Builder::new()
-            .basic_scheduler()
+        let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_io()
             .enable_time()
             .build()
@@ -150,7 +234,7 @@ fn tryagain_simple() {
 
     let requests = atomic::AtomicUsize::new(0);
     let MockEnv {
-        mut runtime,
+        runtime,
         mut connection,
         handler: _handler,
         ..
@@ -180,7 +264,7 @@ fn tryagain_exhaust_retries() {
     let requests = Arc::new(atomic::AtomicUsize::new(0));
 
     let MockEnv {
-        mut runtime,
+        runtime,
         mut client,
         handler: _handler,
         ..
@@ -207,10 +291,144 @@ fn tryagain_exhaust_retries() {
             .query_async::<_, Option<i32>>(&mut connection),
     );
 
+    let err = result.unwrap_err();
+    assert_eq!(err.code(), Some("TRYAGAIN"));
+    assert_eq!(
+        err.to_string(),
+        "An error was signalled by the server: mock"
+    );
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 3);
+}
+
+#[test]
+fn tryagain_policy_delays_the_retries() {
+    let _ = env_logger::try_init();
+    let name = "tryagain_policy_delays_the_retries";
+
+    let requests = atomic::AtomicUsize::new(0);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+
+        match requests.fetch_add(1, atomic::Ordering::SeqCst) {
+            0..=1 => Err(parse_redis_value(b"-TRYAGAIN mock\r\n")),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_tryagain_policy(RetryPolicy::Fixed(Duration::from_millis(50)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn clusterdown_retry_refreshes_slots() {
+    let _ = env_logger::try_init();
+    let name = "clusterdown_retry_refreshes_slots";
+
+    let slot_requests = Arc::new(atomic::AtomicUsize::new(0));
+    let requests = atomic::AtomicUsize::new(0);
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let slot_requests = slot_requests.clone();
+        move |cmd: &[u8], _| {
+            if contains_slice(cmd, b"SLOTS") {
+                slot_requests.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            respond_startup(name, cmd)?;
+
+            match requests.fetch_add(1, atomic::Ordering::SeqCst) {
+                0..=1 => Err(parse_redis_value(b"-CLUSTERDOWN Hash slot not served\r\n")),
+                _ => Err(Ok(Value::Data(b"123".to_vec()))),
+            }
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .set_clusterdown_retry(Some((Duration::from_millis(1), 2)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let initial_slot_requests = slot_requests.load(atomic::Ordering::SeqCst);
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
     assert_eq!(
-        result.map_err(|err| err.to_string()),
-        Err("An error was signalled by the server: mock".to_string())
+        slot_requests.load(atomic::Ordering::SeqCst) - initial_slot_requests,
+        2
     );
+}
+
+#[test]
+fn clusterdown_retry_returns_the_last_error() {
+    let _ = env_logger::try_init();
+    let name = "clusterdown_retry_returns_the_last_error";
+
+    let requests = Arc::new(atomic::AtomicUsize::new(0));
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], _| {
+            respond_startup(name, cmd)?;
+            requests.fetch_add(1, atomic::Ordering::SeqCst);
+            Err(parse_redis_value(b"-CLUSTERDOWN Hash slot not served\r\n"))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_clusterdown_retry(Some((Duration::from_millis(1), 2)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("test")
+                .query_async::<_, Option<i32>>(&mut connection),
+        )
+        .unwrap_err();
+
+    assert_eq!(err.code(), Some("CLUSTERDOWN"));
+    assert_eq!(err.detail(), Some("Hash slot not served"));
     assert_eq!(requests.load(atomic::Ordering::SeqCst), 3);
 }
 
@@ -222,7 +440,7 @@ fn rebuild_with_extra_nodes() {
     let requests = atomic::AtomicUsize::new(0);
     let started = atomic::AtomicBool::new(false);
     let MockEnv {
-        mut runtime,
+        runtime,
         mut connection,
         handler: _handler,
         ..
@@ -232,7 +450,7 @@ fn rebuild_with_extra_nodes() {
         }
         started.store(true, atomic::Ordering::SeqCst);
 
-        if contains_slice(&cmd, b"PING") {
+        if contains_slice(cmd, b"PING") {
             return Err(Ok(Value::Status("OK".into())));
         }
 
@@ -278,3 +496,5364 @@ fn rebuild_with_extra_nodes() {
 
     assert_eq!(value, Ok(Some(123)));
 }
+
+#[test]
+fn pipeline_split_across_nodes() {
+    let _ = env_logger::try_init();
+    let name = "pipeline_split_across_nodes";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    // `foo` is in slot 12182 and `bar` in slot 5061
+    let mut pipe = redis::pipe();
+    pipe.cmd("GET")
+        .arg("foo")
+        .cmd("GET")
+        .arg("bar")
+        .cmd("GET")
+        .arg("foo");
+    let value = runtime.block_on(pipe.query_async::<_, Vec<u16>>(&mut connection));
+
+    assert_eq!(value, Ok(vec![6380, 6379, 6380]));
+}
+
+#[test]
+fn node_for_key_follows_the_slot_map() {
+    let _ = env_logger::try_init();
+    let name = "node_for_key_follows_the_slot_map";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let node = |key: &[u8]| runtime.block_on(connection.node_for_key(key)).unwrap();
+    assert_eq!(node(b"foo"), Some(format!("{}:6380", name)));
+    assert_eq!(node(b"bar"), Some(format!("{}:6379", name)));
+    assert_eq!(node(b"{bar}foo"), Some(format!("{}:6379", name)));
+    assert_eq!(Client::get_slot_for_key(b"{bar}foo"), 5061);
+}
+
+#[test]
+fn explain_route_reports_keys_slot_and_nodes() {
+    let _ = env_logger::try_init();
+    let name = "explain_route_reports_keys_slot_and_nodes";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup_two_nodes(name, cmd)?;
+        panic!("Explaining a route must not send the command");
+    });
+
+    let explain = |cmd: &redis::Cmd| runtime.block_on(connection.explain_route(cmd));
+    let node = |port: u16| format!("{}:{}", name, port);
+
+    let route = explain(cmd("GET").arg("foo")).unwrap();
+    assert_eq!(route.keys(), [b"foo".to_vec()]);
+    assert_eq!(route.slot(), Some(12182));
+    assert_eq!(route.nodes(), [node(6380)]);
+
+    let route = explain(cmd("EVAL").arg("return 1").arg(2).arg("bar").arg("{bar}2")).unwrap();
+    assert_eq!(route.keys(), [b"bar".to_vec(), b"{bar}2".to_vec()]);
+    assert_eq!(route.slot(), Some(5061));
+    assert_eq!(route.nodes(), [node(6379)]);
+
+    // Split by slot
+    let route = explain(cmd("MSET").arg("foo").arg(1).arg("bar").arg(2)).unwrap();
+    assert_eq!(route.keys(), [b"foo".to_vec(), b"bar".to_vec()]);
+    assert_eq!(route.nodes(), [node(6379), node(6380)]);
+
+    let route = explain(cmd("SCRIPT").arg("LOAD").arg("return 1")).unwrap();
+    assert!(route.keys().is_empty());
+    assert_eq!(route.slot(), None);
+    assert_eq!(route.nodes(), [node(6379), node(6380)]);
+
+    let route = explain(&cmd("PING")).unwrap();
+    assert_eq!(route.slot(), None);
+    assert!(route.nodes().is_empty());
+
+    let err = explain(cmd("RENAME").arg("foo").arg("bar")).unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+}
+
+#[test]
+fn slot_count_changes_the_routing() {
+    let _ = env_logger::try_init();
+    let name = "slot_count_changes_the_routing";
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    let handler: Handler = Arc::new(move |cmd, port| {
+        let cmd = cmd.get_packed_command();
+        if contains_slice(&cmd, b"CLUSTER") && contains_slice(&cmd, b"SLOTS") {
+            let slots = |start, end, port| {
+                Value::Bulk(vec![
+                    Value::Int(start),
+                    Value::Int(end),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(port),
+                    ]),
+                ])
+            };
+            return Err(Ok(Value::Bulk(vec![
+                slots(0, 511, 6379),
+                slots(512, 1023, 6380),
+            ])));
+        }
+        respond_startup(name, &cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let mut client = Client::open(vec![&*format!("redis://{}", name)]).unwrap();
+    // The slot map does not cover the 16384 slots of Redis Cluster, "foo" is in slot 12182
+    let connection = runtime
+        .block_on(client.get_generic_connection::<MockConnection>())
+        .unwrap();
+    assert_eq!(runtime.block_on(connection.node_for_key(b"foo")), Ok(None));
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_slot_count(1024)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    // "foo" is in slot 12182 of 16384, 918 of 1024
+    let port = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(port, Ok(6380));
+    let port = runtime.block_on(
+        cmd("GET")
+            .arg("123456789")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(port, Ok(6379));
+    assert_eq!(
+        runtime.block_on(connection.node_for_key(b"foo")).unwrap(),
+        Some(format!("{}:6380", name))
+    );
+}
+
+#[test]
+fn route_to_sends_commands_to_the_given_node() {
+    let _ = env_logger::try_init();
+    let name = "route_to_sends_commands_to_the_given_node";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let port = |addr: String| {
+        let mut node = connection.route_to(addr);
+        runtime.block_on(cmd("DBSIZE").query_async::<_, u16>(&mut node))
+    };
+    assert_eq!(port(format!("{}:6380", name)).unwrap(), 6380);
+    assert_eq!(port(format!("{}:6379", name)).unwrap(), 6379);
+    assert_eq!(
+        port(format!("redis://{}:6380/", name.to_uppercase())).unwrap(),
+        6380
+    );
+    // A key routed to the other node by its slot is still sent to the given node
+    let mut node = connection.route_to(format!("{}:6379", name));
+    let value: u16 = runtime
+        .block_on(cmd("GET").arg("foo").query_async(&mut node))
+        .unwrap();
+    assert_eq!(value, 6379);
+
+    let err = port(format!("{}:6381", name)).unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+}
+
+#[test]
+fn req_raw_returns_the_reply_as_is() {
+    let _ = env_logger::try_init();
+    let name = "req_raw_returns_the_reply_as_is";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Bulk(vec![
+            Value::Int(port.into()),
+            Value::Status("OK".into()),
+            Value::Nil,
+        ])))
+    });
+
+    let reply = |port: u16| {
+        Value::Bulk(vec![
+            Value::Int(port.into()),
+            Value::Status("OK".into()),
+            Value::Nil,
+        ])
+    };
+    let value = runtime.block_on(connection.req_raw(cmd("GET").arg("foo")));
+    assert_eq!(value, Ok(reply(6380)));
+
+    let value =
+        runtime.block_on(connection.req_raw_to(format!("{}:6379", name), cmd("GET").arg("foo")));
+    assert_eq!(value, Ok(reply(6379)));
+
+    let err = runtime
+        .block_on(connection.req_raw_to(format!("{}:6381", name), &cmd("PING")))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+}
+
+#[test]
+fn node_connections_reach_each_node() {
+    let _ = env_logger::try_init();
+    let name = "node_connections_reach_each_node";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let nodes = runtime.block_on(connection.node_connections()).unwrap();
+    let ports: Vec<_> = nodes
+        .into_iter()
+        .map(|(addr, mut node)| {
+            // `foo` belongs to the second node but the command is not routed
+            let port = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut node));
+            (addr, port.unwrap())
+        })
+        .collect();
+    assert_eq!(
+        ports,
+        [
+            (format!("{}:6379", name), 6379),
+            (format!("{}:6380", name), 6380)
+        ]
+    );
+}
+
+#[test]
+fn close_completes_sent_commands_and_fails_later_ones() {
+    let _ = env_logger::try_init();
+    let name = "close_completes_sent_commands_and_fails_later_ones";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        Err(Ok(Value::Data(b"123".to_vec())))
+    });
+
+    let mut sent = connection.clone();
+    let (value, closed) = runtime.block_on(future::join(
+        cmd("GET").arg("test").query_async::<_, String>(&mut sent),
+        connection.clone().close(),
+    ));
+    assert_eq!(value.unwrap(), "123");
+    closed.unwrap();
+
+    let mut later = connection.clone();
+    let err = runtime
+        .block_on(cmd("GET").arg("test").query_async::<_, String>(&mut later))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+    assert_eq!(err.to_string(), "Connection closed");
+    assert!(runtime.block_on(connection.pool_stats()).is_err());
+}
+
+#[test]
+fn max_inflight_per_connection_holds_back_requests() {
+    let _ = env_logger::try_init();
+    let name = "max_inflight_per_connection_holds_back_requests";
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            requests
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(cmd).into_owned());
+            if contains_slice(cmd, b"slow") {
+                return Err(Ok(Value::Status(STALL.into())));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_max_inflight_per_connection(Some(1))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let get = |key: &'static str| {
+        let mut connection = connection.clone();
+        Box::pin(async move {
+            cmd("GET")
+                .arg(key)
+                .query_async::<_, u16>(&mut connection)
+                .await
+        })
+    };
+    let wait = || Box::pin(tokio::time::sleep(Duration::from_millis(50)));
+
+    runtime.block_on(async {
+        // The stalled request keeps the only permit of the connection
+        let slow = match future::select(get("slow"), wait()).await {
+            future::Either::Right((_, slow)) => slow,
+            future::Either::Left(_) => panic!("The request completed"),
+        };
+        let fast = match future::select(get("fast"), wait()).await {
+            future::Either::Right((_, fast)) => fast,
+            future::Either::Left(_) => panic!("The request was not held back"),
+        };
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.contains("fast")));
+
+        // Dropping the stalled request releases its permit
+        drop(slow);
+        assert_eq!(fast.await, Ok(6379));
+    });
+}
+
+#[test]
+fn full_node_queue_fails_the_requests_to_the_node() {
+    let _ = env_logger::try_init();
+    let name = "full_node_queue_fails_the_requests_to_the_node";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        if port == 6380 {
+            return Err(Ok(Value::Status(STALL.into())));
+        }
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_node_queue_capacity(Some(1))
+                .set_node_queue_full_mode(QueueFullMode::Fail)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let get = |key: &'static str| {
+        let mut connection = connection.clone();
+        Box::pin(async move {
+            cmd("GET")
+                .arg(key)
+                .query_async::<_, u16>(&mut connection)
+                .await
+        })
+    };
+    let wait = || Box::pin(tokio::time::sleep(Duration::from_millis(50)));
+
+    runtime.block_on(async {
+        // The stalled request takes the only place in the queue of 6380
+        let stalled = match future::select(get("foo"), wait()).await {
+            future::Either::Right((_, stalled)) => stalled,
+            future::Either::Left(_) => panic!("The request completed"),
+        };
+        let err = get("foo").await.unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Node overloaded: {}:6380 has too many requests queued",
+                name
+            )
+        );
+        assert_eq!(get("bar").await, Ok(6379));
+
+        // Dropping the stalled request frees its place
+        drop(stalled);
+        match future::select(get("foo"), wait()).await {
+            future::Either::Right(_) => (),
+            future::Either::Left((result, _)) => panic!("The request completed: {:?}", result),
+        }
+    });
+}
+
+#[test]
+fn fanout_concurrency_bounds_the_split_requests() {
+    let _ = env_logger::try_init();
+    let name = "fanout_concurrency_bounds_the_split_requests";
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            requests
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(cmd).into_owned());
+            if contains_slice(cmd, b"slow") {
+                return Err(Ok(Value::Status(STALL.into())));
+            }
+            if contains_slice(cmd, b"MGET") {
+                return Err(Ok(Value::Bulk(vec![Value::Int(port.into())])));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_fanout_concurrency(Some(1))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    // Both keys are in different slots, the command is split in two
+    let mget = |prefix: &'static str| {
+        let mut connection = connection.clone();
+        Box::pin(async move {
+            cmd("MGET")
+                .arg(format!("{}1", prefix))
+                .arg(format!("{}2", prefix))
+                .query_async::<_, Vec<u16>>(&mut connection)
+                .await
+        })
+    };
+    let wait = || Box::pin(tokio::time::sleep(Duration::from_millis(50)));
+
+    runtime.block_on(async {
+        // One part of the stalled command keeps the only permit, the other waits for it
+        let slow = match future::select(mget("slow"), wait()).await {
+            future::Either::Right((_, slow)) => slow,
+            future::Either::Left(_) => panic!("The command completed"),
+        };
+        assert_eq!(
+            requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.contains("slow"))
+                .count(),
+            1
+        );
+        let fast = match future::select(mget("fast"), wait()).await {
+            future::Either::Right((_, fast)) => fast,
+            future::Either::Left(_) => panic!("The command was not held back"),
+        };
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.contains("fast")));
+
+        // Commands which are not split do not wait
+        let mut single = connection.clone();
+        let value = cmd("GET")
+            .arg("other")
+            .query_async::<_, u16>(&mut single)
+            .await;
+        assert_eq!(value, Ok(6379));
+
+        // Dropping the stalled command releases the permit
+        drop(slow);
+        assert_eq!(fast.await, Ok(vec![6379, 6379]));
+    });
+}
+
+#[test]
+fn broadcast_reports_each_master() {
+    let _ = env_logger::try_init();
+    let name = "broadcast_reports_each_master";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        if contains_slice(cmd, b"CONFIG") && port == 6380 {
+            Err(parse_redis_value(b"-ERR mock\r\n"))
+        } else {
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    assert_eq!(runtime.block_on(connection.dbsize()).unwrap(), 6379 + 6380);
+
+    let results = runtime
+        .block_on(connection.broadcast(cmd("CONFIG").arg("SET").arg("maxmemory").arg(0)))
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(
+        results[&format!("{}:6379", name)].as_ref().unwrap(),
+        &Value::Int(6379)
+    );
+    assert!(results[&format!("{}:6380", name)].is_err());
+}
+
+#[test]
+fn master_handles_query_their_own_master() {
+    let _ = env_logger::try_init();
+    let name = "master_handles_query_their_own_master";
+
+    let single_master = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let single_master = single_master.clone();
+        move |cmd: &[u8], port| {
+            if single_master.load(atomic::Ordering::SeqCst)
+                && contains_slice(cmd, b"CLUSTER")
+                && contains_slice(cmd, b"SLOTS")
+            {
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6379),
+                    ]),
+                ])])));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let mut masters = runtime.block_on(connection.masters()).unwrap();
+    masters.sort_by(|a, b| a.addr().cmp(b.addr()));
+    let addrs: Vec<_> = masters
+        .iter()
+        .map(|master| master.addr().to_string())
+        .collect();
+    assert_eq!(addrs, [format!("{}:6379", name), format!("{}:6380", name)]);
+    for (master, port) in masters.iter_mut().zip([6379, 6380]) {
+        // Sent to the master of the handle rather than to the master of the key
+        let reply = runtime.block_on(master.query_async::<u16>(cmd("GET").arg("foo")));
+        assert_eq!(reply, Ok(port));
+    }
+
+    single_master.store(true, atomic::Ordering::SeqCst);
+    runtime.block_on(connection.refresh_topology()).unwrap();
+    let reply = runtime.block_on(masters[0].query_async::<u16>(&cmd("DBSIZE")));
+    assert_eq!(reply, Ok(6379));
+    let err = runtime
+        .block_on(masters[1].query_async::<u16>(&cmd("DBSIZE")))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+}
+
+#[test]
+fn client_name_is_set_on_every_connection() {
+    let _ = env_logger::try_init();
+    let name = "client_name_is_set_on_every_connection";
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let handler: Handler = Arc::new({
+        let names = names.clone();
+        move |cmd, port| {
+            let packed = cmd.get_packed_command();
+            if contains_slice(&packed, b"SETNAME") {
+                let args: Vec<String> =
+                    redis::from_redis_value(&parse_redis_value(&packed).unwrap()).unwrap();
+                names.lock().unwrap().push((port, args[2].clone()));
+                // The second node rejects the name, its connection is used anyway
+                return match port {
+                    6380 => Err(parse_redis_value(b"-ERR mock\r\n")),
+                    _ => Err(Ok(Value::Okay)),
+                };
+            }
+            respond_startup_two_nodes(name, &packed)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let mut connection = runtime
+        .block_on(
+            Client::builder(vec![&*format!("redis://{}", name)])
+                .unwrap()
+                .client_name("service")
+                .client_name_suffix(true)
+                .build()
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let port = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(port, Ok(6380));
+    let names = names.lock().unwrap();
+    assert_eq!(
+        *names,
+        [
+            (6379, "service-1".to_string()),
+            (6380, "service-2".to_string())
+        ]
+    );
+}
+
+#[test]
+fn connection_failures_name_the_node() {
+    let _ = env_logger::try_init();
+    let name = "connection_failures_name_the_node";
+
+    let refuse = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let refuse = refuse.clone();
+        move |cmd: &[u8], port| {
+            if port == 6380 && contains_slice(cmd, b"PING") && refuse.load(atomic::Ordering::SeqCst)
+            {
+                return Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionRefused,
+                )
+                .into()));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    refuse.store(true, atomic::Ordering::SeqCst);
+    let err = runtime
+        .block_on(connection.take_dedicated("foo"))
+        .err()
+        .unwrap();
+    assert!(err.is_connection_refusal(), "{}", err);
+    assert!(
+        err.to_string().starts_with(&format!("{}:6380: ", name)),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn reset_node_connections_sets_the_connections_up_again() {
+    let _ = env_logger::try_init();
+    let name = "reset_node_connections_sets_the_connections_up_again";
+
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let commands = commands.clone();
+        move |cmd: &[u8], port| {
+            for command in &["PING", "RESET", "SELECT"] {
+                if contains_slice(cmd, command.as_bytes()) {
+                    commands.lock().unwrap().push((port, *command));
+                }
+            }
+            // The second node runs a version of Redis without `RESET`
+            if contains_slice(cmd, b"RESET") {
+                return match port {
+                    6379 => Err(Ok(Value::Status("RESET".into()))),
+                    _ => Err(parse_redis_value(b"-ERR unknown command `RESET`\r\n")),
+                };
+            }
+            if contains_slice(cmd, b"SELECT") {
+                return Err(Ok(Value::Okay));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(client.set_db(1).get_generic_connection::<MockConnection>())
+        .unwrap();
+    commands.lock().unwrap().clear();
+
+    let results = runtime
+        .block_on(connection.reset_node_connections())
+        .unwrap();
+    assert_eq!(
+        results
+            .iter()
+            .map(|(addr, result)| (addr.clone(), result.is_ok()))
+            .collect::<Vec<_>>(),
+        [
+            (format!("{}:6379", name), true),
+            (format!("{}:6380", name), true)
+        ]
+    );
+    // The first node selects the database again over the same connection
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [
+            (6379, "RESET"),
+            (6379, "SELECT"),
+            (6379, "PING"),
+            (6380, "RESET")
+        ]
+    );
+
+    // The connection to the second node is replaced
+    commands.lock().unwrap().clear();
+    let value = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(value, Ok(6380));
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [(6380, "SELECT"), (6380, "PING")]
+    );
+}
+
+#[test]
+fn take_dedicated_connects_to_the_master_of_the_slot() {
+    let _ = env_logger::try_init();
+    let name = "take_dedicated_connects_to_the_master_of_the_slot";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let mut dedicated = runtime.block_on(connection.take_dedicated("foo")).unwrap();
+    assert_eq!(dedicated.node(), format!("{}:6380", name));
+    let port = runtime.block_on(
+        cmd("BLPOP")
+            .arg("foo")
+            .arg(0)
+            .query_async::<_, u16>(&mut dedicated),
+    );
+    assert_eq!(port, Ok(6380));
+    drop(dedicated);
+
+    // The connection returned to the pool is handed out again
+    let mut dedicated = runtime.block_on(connection.take_dedicated(8192)).unwrap();
+    assert_eq!(dedicated.node(), format!("{}:6380", name));
+    let mut other = runtime.block_on(connection.take_dedicated(0)).unwrap();
+    assert_eq!(other.node(), format!("{}:6379", name));
+    let brpop = cmd("BRPOP").arg("bar").arg(0).clone();
+    let xread = cmd("XREAD")
+        .arg("BLOCK")
+        .arg(0)
+        .arg("STREAMS")
+        .arg("foo")
+        .arg("$")
+        .clone();
+    let ports = runtime.block_on(future::try_join(
+        brpop.query_async::<_, u16>(&mut other),
+        xread.query_async::<_, u16>(&mut dedicated),
+    ));
+    assert_eq!(ports, Ok((6379, 6380)));
+}
+
+#[test]
+fn pinned_connection_stays_on_the_master_of_the_slot() {
+    let _ = env_logger::try_init();
+    let name = "pinned_connection_stays_on_the_master_of_the_slot";
+
+    // Once set, the first node serves every slot
+    let moved = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let moved = moved.clone();
+        move |cmd: &[u8], port| {
+            let moved = moved.load(atomic::Ordering::SeqCst);
+            if moved {
+                respond_startup(name, cmd)?;
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            if moved && port == 6380 {
+                Err(parse_redis_value(
+                    format!("-MOVED 12182 {}:6379\r\n", name).as_bytes(),
+                ))
+            } else {
+                Err(Ok(Value::Int(port.into())))
+            }
+        }
+    });
+
+    let mut pinned = connection.pinned(Client::get_slot_for_key(b"foo"));
+    assert_eq!(pinned.node(), None);
+    let port: u16 = runtime
+        .block_on(cmd("SET").arg("foo").arg(1).query_async(&mut pinned))
+        .unwrap();
+    assert_eq!(port, 6380);
+    // `WAIT` has no key but follows the write
+    let port: u16 = runtime
+        .block_on(cmd("WAIT").arg(1).arg(0).query_async(&mut pinned))
+        .unwrap();
+    assert_eq!(port, 6380);
+    assert_eq!(pinned.node(), Some(&*format!("{}:6380", name)));
+
+    moved.store(true, atomic::Ordering::SeqCst);
+    let port: u16 = runtime
+        .block_on(cmd("GET").arg("foo").query_async(&mut connection))
+        .unwrap();
+    assert_eq!(port, 6379);
+
+    let err = runtime
+        .block_on(cmd("WAIT").arg(1).arg(0).query_async::<_, u16>(&mut pinned))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+}
+
+#[test]
+fn clones_share_the_slot_map() {
+    let _ = env_logger::try_init();
+    let name = "clones_share_the_slot_map";
+
+    // Once set, the first node serves every slot
+    let moved = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let moved = moved.clone();
+        move |cmd: &[u8], port| {
+            let moved = moved.load(atomic::Ordering::SeqCst);
+            if moved {
+                respond_startup(name, cmd)?;
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            if moved && port == 6380 {
+                Err(parse_redis_value(
+                    format!("-MOVED 12182 {}:6379\r\n", name).as_bytes(),
+                ))
+            } else {
+                Err(Ok(Value::Int(port.into())))
+            }
+        }
+    });
+
+    let mut clone = connection.clone();
+    moved.store(true, atomic::Ordering::SeqCst);
+    let port: u16 = runtime
+        .block_on(cmd("GET").arg("foo").query_async(&mut clone))
+        .unwrap();
+    assert_eq!(port, 6379);
+    drop(clone);
+
+    // The redirection seen by the clone updated the slots of the original connection
+    assert_eq!(
+        runtime.block_on(connection.node_for_key(b"foo")),
+        Ok(Some(format!("{}:6379", name)))
+    );
+    let stats = runtime.block_on(connection.pool_stats()).unwrap();
+    assert!(stats.contains_key(&format!("{}:6379", name)));
+}
+
+#[test]
+fn db_is_selected_on_every_connection() {
+    let _ = env_logger::try_init();
+    let name = "db_is_selected_on_every_connection";
+
+    let selects = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let selects = selects.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            if contains_slice(cmd, b"SELECT") {
+                selects.lock().unwrap().push(port);
+                if !contains_slice(cmd, b"$1\r\n3\r\n") {
+                    return Err(parse_redis_value(
+                        b"-ERR SELECT is not allowed in cluster mode\r\n",
+                    ));
+                }
+                return Err(Ok(Value::Okay));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let client = Client::open(vec![&*format!("redis://{}/3", name)]).unwrap();
+    let mut connection = runtime
+        .block_on(client.get_generic_connection::<MockConnection>())
+        .unwrap();
+    for (key, port) in &[("foo", 6380), ("bar", 6379)] {
+        let value = runtime.block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection));
+        assert_eq!(value, Ok(*port));
+    }
+    let mut ports = selects.lock().unwrap().clone();
+    ports.sort_unstable();
+    assert_eq!(ports, [6379, 6380]);
+
+    let mut client = Client::open(vec![&*format!("redis://{}", name)]).unwrap();
+    let err = runtime
+        .block_on(client.set_db(1).get_generic_connection::<MockConnection>())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+    assert!(err.to_string().contains("SELECT is not allowed"), "{}", err);
+}
+
+#[test]
+fn client_flags_are_set_on_every_connection() {
+    let _ = env_logger::try_init();
+    let name = "client_flags_are_set_on_every_connection";
+
+    let flags = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let flags = flags.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            for flag in &["NO-EVICT", "NO-TOUCH"] {
+                if contains_slice(cmd, flag.as_bytes()) {
+                    assert!(contains_slice(cmd, b"ON"));
+                    flags.lock().unwrap().push((port, *flag));
+                    // The node on port 6380 runs a version without `NO-TOUCH`
+                    if port == 6380 && *flag == "NO-TOUCH" {
+                        return Err(parse_redis_value(b"-ERR unknown subcommand 'NO-TOUCH'\r\n"));
+                    }
+                    return Err(Ok(Value::Okay));
+                }
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_client_flags(ClientFlags::new().with_no_evict().with_no_touch())
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    for (key, port) in &[("foo", 6380), ("bar", 6379)] {
+        let value = runtime.block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection));
+        assert_eq!(value, Ok(*port));
+    }
+    let mut flags = flags.lock().unwrap().clone();
+    flags.sort_unstable();
+    flags.dedup();
+    assert_eq!(
+        flags,
+        [
+            (6379, "NO-EVICT"),
+            (6379, "NO-TOUCH"),
+            (6380, "NO-EVICT"),
+            (6380, "NO-TOUCH")
+        ]
+    );
+}
+
+#[test]
+fn on_connect_runs_on_every_connection() {
+    let _ = env_logger::try_init();
+    let name = "on_connect_runs_on_every_connection";
+
+    let setups = Arc::new(Mutex::new(Vec::new()));
+    let gets = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let setups = setups.clone();
+        let gets = gets.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            if contains_slice(cmd, b"SETINFO") {
+                setups.lock().unwrap().push(port);
+                // The node on port 6380 refuses the setting
+                if port == 6380 && contains_slice(cmd, b"strict") {
+                    return Err(parse_redis_value(b"-ERR refused\r\n"));
+                }
+                return Err(Ok(Value::Okay));
+            }
+            gets.lock().unwrap().push(port);
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_on_connect(|mut connection| {
+                    Box::pin(async move {
+                        cmd("CLIENT")
+                            .arg("SETINFO")
+                            .arg("LIB-NAME")
+                            .arg("mock")
+                            .query_async(&mut connection)
+                            .await
+                    })
+                })
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    for (key, port) in &[("foo", 6380), ("bar", 6379)] {
+        let value = runtime.block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection));
+        assert_eq!(value, Ok(*port));
+    }
+    let mut set_up = setups.lock().unwrap().clone();
+    set_up.sort_unstable();
+    set_up.dedup();
+    assert_eq!(set_up, [6379, 6380]);
+
+    // A failure of the hook fails the connection, no request is sent over it
+    setups.lock().unwrap().clear();
+    gets.lock().unwrap().clear();
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .set_on_connect(|mut connection| {
+                    Box::pin(async move {
+                        cmd("CLIENT")
+                            .arg("SETINFO")
+                            .arg("LIB-NAME")
+                            .arg("strict")
+                            .query_async(&mut connection)
+                            .await
+                    })
+                })
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    for key in &["foo", "bar"] {
+        let _ = runtime.block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection));
+    }
+    assert!(setups.lock().unwrap().contains(&6380));
+    assert!(!gets.lock().unwrap().contains(&6380));
+}
+
+#[test]
+fn update_credentials_rolls_back_when_a_node_rejects_them() {
+    let _ = env_logger::try_init();
+    let name = "update_credentials_rolls_back_when_a_node_rejects_them";
+
+    let auths = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let auths = auths.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            if contains_slice(cmd, b"AUTH") {
+                let password = if contains_slice(cmd, b"newpass") {
+                    "newpass"
+                } else {
+                    "bad"
+                };
+                auths.lock().unwrap().push((port, password));
+                if port == 6380 && password == "bad" {
+                    return Err(parse_redis_value(
+                        b"-WRONGPASS invalid username-password pair\r\n",
+                    ));
+                }
+                return Err(Ok(Value::Okay));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    for key in &["foo", "bar"] {
+        runtime
+            .block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection))
+            .unwrap();
+    }
+    runtime
+        .block_on(connection.update_credentials(None, "newpass"))
+        .unwrap();
+    let mut received = auths.lock().unwrap().drain(..).collect::<Vec<_>>();
+    received.sort_unstable();
+    assert_eq!(received, [(6379, "newpass"), (6380, "newpass")]);
+
+    let err = runtime
+        .block_on(connection.update_credentials(None, "bad"))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::AuthenticationFailed);
+    assert!(err.to_string().contains(":6380"), "{}", err);
+    let mut received = auths.lock().unwrap().drain(..).collect::<Vec<_>>();
+    received.sort_unstable();
+    assert_eq!(received, [(6379, "bad"), (6379, "newpass"), (6380, "bad")]);
+
+    let value = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(value, Ok(6380));
+}
+
+#[test]
+fn slot_key_counts_asks_the_master_of_every_slot() {
+    let _ = env_logger::try_init();
+    let name = "slot_key_counts_asks_the_master_of_every_slot";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        if contains_slice(cmd, b"COUNTKEYSINSLOT") {
+            return Err(Ok(Value::Int(i64::from(port) - 6378)));
+        }
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let keys = runtime.block_on(
+        cmd("CLUSTER")
+            .arg("COUNTKEYSINSLOT")
+            .arg(16000)
+            .query_async::<_, i64>(&mut connection),
+    );
+    assert_eq!(keys, Ok(2));
+
+    let counts = runtime.block_on(connection.slot_key_counts()).unwrap();
+    assert_eq!(counts.len(), 16384);
+    assert!(counts
+        .iter()
+        .enumerate()
+        .all(|(i, count)| count.slot() as usize == i));
+    assert_eq!(counts[0].node(), format!("{}:6379", name));
+    assert_eq!(counts[0].keys(), 1);
+    assert_eq!(counts[16383].node(), format!("{}:6380", name));
+    assert_eq!(counts[16383].keys(), 2);
+    assert_eq!(
+        counts.iter().map(|count| count.keys()).sum::<i64>(),
+        8192 * 3
+    );
+
+    let distribution = runtime.block_on(connection.key_distribution()).unwrap();
+    assert_eq!(distribution.len(), 2);
+    assert_eq!(distribution[&format!("{}:6379", name)], 6379);
+    assert_eq!(distribution[&format!("{}:6380", name)], 6380);
+}
+
+#[test]
+fn bulk_set_reports_the_failed_pipelines() {
+    let _ = env_logger::try_init();
+    let name = "bulk_set_reports_the_failed_pipelines";
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let received = received.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let command = if contains_slice(cmd, b"MSET") {
+                "MSET"
+            } else {
+                "SET"
+            };
+            received.lock().unwrap().push((port, command));
+            match port {
+                6380 => Err(parse_redis_value(b"-OOM command not allowed\r\n")),
+                _ => Err(Ok(Value::Okay)),
+            }
+        }
+    });
+
+    // `bar` and `{bar}1` share a hash tag and go together in the first batch, `foo` is served by
+    // the node on port 6380, which fails
+    let pairs = vec![("bar", 1), ("{bar}1", 2), ("{bar}2", 3), ("foo", 4)];
+    let report = runtime.block_on(connection.bulk_set(pairs, 2)).unwrap();
+    assert_eq!(report.written(), 3);
+    assert_eq!(report.failed(), 1);
+    let errors: Vec<_> = report
+        .errors()
+        .iter()
+        .map(|(node, err)| (node.clone(), err.code()))
+        .collect();
+    assert_eq!(errors, [(Some(format!("{}:6380", name)), Some("OOM"))]);
+
+    let mut received = received.lock().unwrap().clone();
+    received[1..].sort_unstable();
+    assert_eq!(received, [(6379, "MSET"), (6379, "SET"), (6380, "SET")]);
+}
+
+#[test]
+fn bulk_set_groups_the_keys_by_the_configured_slots() {
+    let _ = env_logger::try_init();
+    let name = "bulk_set_groups_the_keys_by_the_configured_slots";
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let received = received.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let command = if contains_slice(cmd, b"MSET") {
+                "MSET"
+            } else {
+                "SET"
+            };
+            received.lock().unwrap().push((port, command));
+            Err(Ok(Value::Okay))
+        }
+    });
+
+    // The slot of a key is the one of its first three bytes
+    let connection = runtime
+        .block_on(
+            client
+                .set_hash_key(|key| &key[..key.len().min(3)])
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let pairs = vec![("bar1", 1), ("bar2", 2), ("foo1", 3)];
+    let report = runtime.block_on(connection.bulk_set(pairs, 3)).unwrap();
+    assert_eq!(report.written(), 3);
+
+    let mut received = received.lock().unwrap().clone();
+    received.sort_unstable();
+    assert_eq!(received, [(6379, "MSET"), (6380, "SET")]);
+}
+
+#[test]
+fn slot_state_reports_the_migrations() {
+    let _ = env_logger::try_init();
+    let name = "slot_state_reports_the_migrations";
+
+    // 0: slot 5061 is migrating from 6379 to 6380, 1: 6380 still imports it, 2: stable
+    let state = Arc::new(atomic::AtomicUsize::new(0));
+    // Replies of 6379 after which the migration is over, see `wait_slot_stable`
+    let migrating_replies = Arc::new(atomic::AtomicUsize::new(usize::MAX));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let state = state.clone();
+        let migrating_replies = migrating_replies.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            assert!(contains_slice(cmd, b"NODES"));
+            if port == 6379 && migrating_replies.fetch_sub(1, atomic::Ordering::SeqCst) == 1 {
+                state.store(2, atomic::Ordering::SeqCst);
+            }
+            let state = state.load(atomic::Ordering::SeqCst);
+            let own_slots = match (port, state) {
+                (6379, 0) => "0-8191 [5061->-id2]",
+                (6379, _) => "0-8191",
+                (_, 2) => "8192-16383",
+                _ => "8192-16383 [5061-<-id1]",
+            };
+            let nodes = [6379, 6380]
+                .iter()
+                .map(|&node| {
+                    let flags = if node == port {
+                        "myself,master"
+                    } else {
+                        "master"
+                    };
+                    let slots = if node == port { own_slots } else { "0-16383" };
+                    format!(
+                        "id{} {}:{}@1{} {} - 0 0 1 connected {}\n",
+                        node - 6378,
+                        name,
+                        node,
+                        node,
+                        flags,
+                        slots
+                    )
+                })
+                .collect::<String>();
+            Err(Ok(Value::Data(nodes.into_bytes())))
+        }
+    });
+
+    let slot_state = |slot| {
+        runtime
+            .block_on(connection.clone().slot_state(slot))
+            .unwrap()
+    };
+    assert_eq!(slot_state(5061), SlotState::Migrating);
+    assert_eq!(slot_state(5062), SlotState::Stable);
+    state.store(1, atomic::Ordering::SeqCst);
+    assert_eq!(slot_state(5061), SlotState::Importing);
+    state.store(2, atomic::Ordering::SeqCst);
+    assert_eq!(slot_state(5061), SlotState::Stable);
+
+    state.store(0, atomic::Ordering::SeqCst);
+    let err = runtime
+        .block_on(connection.wait_slot_stable(5061, Duration::from_millis(50)))
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::TryAgain);
+
+    migrating_replies.store(3, atomic::Ordering::SeqCst);
+    let waited = runtime.block_on(connection.wait_slot_stable(5061, Duration::from_secs(5)));
+    assert_eq!(waited, Ok(()));
+    assert_eq!(state.load(atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn verify_keyslot_compares_with_the_cluster() {
+    let _ = env_logger::try_init();
+    let name = "verify_keyslot_compares_with_the_cluster";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup_two_nodes(name, cmd)?;
+        if contains_slice(cmd, b"KEYSLOT") {
+            // The node hashes `{user}` keys alike and every other key to slot 0
+            if contains_slice(cmd, b"{user}") {
+                return Err(Ok(Value::Int(5474)));
+            }
+            return Err(Ok(Value::Int(0)));
+        }
+        Err(Ok(Value::Nil))
+    });
+
+    let check = runtime
+        .block_on(connection.verify_keyslot(b"{user}1000"))
+        .unwrap();
+    assert_eq!((check.local(), check.server()), (5474, 5474));
+    assert!(check.matches());
+
+    let check = runtime.block_on(connection.verify_keyslot(b"foo")).unwrap();
+    assert_eq!((check.local(), check.server()), (12182, 0));
+    assert!(!check.matches());
+}
+
+#[test]
+fn cluster_info_parses_the_fields() {
+    let _ = env_logger::try_init();
+    let name = "cluster_info_parses_the_fields";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        if contains_slice(cmd, b"INFO") {
+            let info = "cluster_state:fail\r\ncluster_slots_assigned:16384\r\n\
+                        cluster_slots_ok:10923\r\ncluster_slots_pfail:0\r\n\
+                        cluster_slots_fail:5461\r\ncluster_known_nodes:6\r\n\
+                        cluster_size:3\r\ncluster_current_epoch:7\r\ncluster_my_epoch:2\r\n\
+                        cluster_stats_messages_sent:1483972\r\n";
+            return Err(Ok(Value::Data(info.as_bytes().to_vec())));
+        }
+        Err(Ok(Value::Nil))
+    });
+
+    let info = runtime.block_on(connection.cluster_info()).unwrap();
+    assert!(!info.is_ok());
+    assert_eq!(info.state(), "fail");
+    assert_eq!(
+        (info.slots_assigned(), info.slots_ok(), info.slots_pfail()),
+        (16384, 10923, 0)
+    );
+    assert_eq!(info.slots_fail(), 5461);
+    assert_eq!((info.known_nodes(), info.size()), (6, 3));
+    assert_eq!((info.current_epoch(), info.my_epoch()), (7, 2));
+    assert_eq!(info.get("cluster_stats_messages_sent"), Some("1483972"));
+    assert_eq!(info.get("cluster_stats_messages_received"), None);
+}
+
+#[test]
+fn connect_config_bounds_the_bootstrap() {
+    let _ = env_logger::try_init();
+    let name = "connect_config_bounds_the_bootstrap";
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    // The second seed never answers, the first one fails its first two checks
+    let pings = Arc::new(atomic::AtomicUsize::new(0));
+    let handler: Handler = Arc::new({
+        let pings = pings.clone();
+        move |cmd, port| {
+            let cmd = cmd.get_packed_command();
+            if contains_slice(&cmd, b"PING")
+                && (port == 6381 || pings.fetch_add(1, atomic::Ordering::SeqCst) < 2)
+            {
+                return Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionRefused,
+                )
+                .into()));
+            }
+            respond_startup(name, &cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let client = Client::open(vec![
+        &*format!("redis://{}:6379", name),
+        &*format!("redis://{}:6381", name),
+    ])
+    .unwrap();
+    let connect = |config| {
+        runtime.block_on(client.get_generic_connection_with_config::<MockConnection>(config))
+    };
+
+    let err = connect(ConnectConfig::new()).err().unwrap();
+    let mut failed: Vec<_> = err.node_errors().iter().map(|(addr, _)| addr).collect();
+    failed.sort();
+    assert_eq!(
+        failed,
+        [&format!("{}:6379", name), &format!("{}:6381", name)]
+    );
+    // The message lists the failure of each seed
+    let message = err.to_string();
+    assert!(message.contains(&format!("{}:6379: ", name)), "{}", message);
+    assert!(message.contains(&format!("{}:6381: ", name)), "{}", message);
+
+    let mut connection = connect(
+        ConnectConfig::new()
+            .with_max_attempts(2)
+            .with_retry_delay(Duration::from_millis(1)),
+    )
+    .unwrap();
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6379));
+
+    let err = connect(ConnectConfig::new().with_all_nodes_required(true))
+        .err()
+        .unwrap();
+    assert_eq!(err.node_errors().len(), 1);
+    assert_eq!(err.node_errors()[0].0, format!("{}:6381", name));
+}
+
+#[test]
+fn bootstrap_timeout_waits_for_every_slot() {
+    let _ = env_logger::try_init();
+    let name = "bootstrap_timeout_waits_for_every_slot";
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    // The slots of the second half are missing from the next `missing` slot maps
+    let missing = Arc::new(atomic::AtomicUsize::new(usize::MAX));
+    let handler: Handler = Arc::new({
+        let missing = missing.clone();
+        move |cmd, port| {
+            let cmd = cmd.get_packed_command();
+            if contains_slice(&cmd, b"CLUSTER")
+                && contains_slice(&cmd, b"SLOTS")
+                && missing.load(atomic::Ordering::SeqCst) > 0
+            {
+                missing.fetch_sub(1, atomic::Ordering::SeqCst);
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(8191),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6379),
+                    ]),
+                ])])));
+            }
+            respond_startup_two_nodes(name, &cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let mut client = Client::open(vec![&*format!("redis://{}:6379", name)]).unwrap();
+    client.set_bootstrap_timeout(Some(Duration::from_millis(100)));
+    let config = || ConnectConfig::new().with_retry_delay(Duration::from_millis(10));
+
+    let started = std::time::Instant::now();
+    let err = runtime
+        .block_on(client.get_generic_connection_with_config::<MockConnection>(config()))
+        .err()
+        .expect("connection to a cluster missing slots");
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert!(err.is_timeout(), "{}", *err);
+    // The error describes the slots found last
+    let message = err.to_string();
+    assert!(
+        message.contains(&format!("0-8191 at {}:6379", name)),
+        "{}",
+        message
+    );
+    assert!(message.contains("8192-16383 unserved"), "{}", message);
+
+    // The attempts go on until every slot is served
+    missing.store(3, atomic::Ordering::SeqCst);
+    let mut connection = runtime
+        .block_on(client.get_generic_connection_with_config::<MockConnection>(config()))
+        .unwrap();
+    assert_eq!(missing.load(atomic::Ordering::SeqCst), 0);
+    let value = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(value, Ok(6380));
+}
+
+#[test]
+fn seed_strategy_decides_how_many_seeds_must_answer() {
+    let _ = env_logger::try_init();
+    let name = "seed_strategy_decides_how_many_seeds_must_answer";
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    // 6380 never answers, 6381 and 6382 refuse the connections
+    let handler: Handler = Arc::new(move |cmd, port| {
+        let cmd = cmd.get_packed_command();
+        if contains_slice(&cmd, b"PING") {
+            match port {
+                6380 => return Err(Ok(Value::Status(STALL.into()))),
+                6381 | 6382 => {
+                    return Err(Err(std::io::Error::from(
+                        std::io::ErrorKind::ConnectionRefused,
+                    )
+                    .into()))
+                }
+                _ => (),
+            }
+        }
+        respond_startup(name, &cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let connect = |ports: &[u16], seed_strategy| {
+        let nodes: Vec<_> = ports
+            .iter()
+            .map(|port| format!("redis://{}:{}", name, port))
+            .collect();
+        let client = Client::builder(nodes.iter().map(|node| &**node).collect::<Vec<_>>())
+            .unwrap()
+            .seed_strategy(seed_strategy)
+            .build();
+        runtime.block_on(client.get_generic_connection::<MockConnection>())
+    };
+
+    // The stalled seed is not waited for
+    let mut connection = connect(&[6379, 6380, 6381], SeedStrategy::FirstReachable).unwrap();
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6379));
+
+    connect(&[6379, 6381, 6383], SeedStrategy::Quorum).unwrap();
+
+    let err = connect(&[6379, 6381, 6382], SeedStrategy::Quorum)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().contains("quorum"),
+        "unexpected error: {}",
+        err
+    );
+    assert!(err.to_string().contains(&format!("{}:6382", name)));
+}
+
+#[test]
+fn topology_snapshot_reports_the_slot_map() {
+    let _ = env_logger::try_init();
+    let name = "topology_snapshot_reports_the_slot_map";
+
+    let before = SystemTime::now();
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let topology = runtime.block_on(connection.topology_snapshot()).unwrap();
+    let shards: Vec<_> = topology
+        .shards()
+        .iter()
+        .map(|shard| {
+            (
+                shard.master().to_string(),
+                shard.replicas().len(),
+                shard.slots().to_vec(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        shards,
+        [
+            (format!("{}:6379", name), 0, vec![(0, 8191)]),
+            (format!("{}:6380", name), 0, vec![(8192, 16383)]),
+        ]
+    );
+    assert!(topology.refreshed_at() >= before);
+    assert!(topology.refreshed_at() <= SystemTime::now());
+}
+
+#[test]
+fn node_address_mapper_translates_announced_addresses() {
+    let _ = env_logger::try_init();
+    let name = "node_address_mapper_translates_announced_addresses";
+
+    // `MockEnv` would connect without the mapper
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+    let handler: Handler = Arc::new(move |cmd, port| {
+        let cmd = cmd.get_packed_command();
+        if contains_slice(&cmd, b"PING") {
+            Err(Ok(Value::Status("OK".into())))
+        } else if contains_slice(&cmd, b"SLOTS") {
+            // The only master announces an address which is not reachable from the client
+            Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(16383),
+                Value::Bulk(vec![Value::Data(b"10.0.0.1".to_vec()), Value::Int(7000)]),
+            ])])))
+        } else {
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+    HANDLERS.write().unwrap().insert(name.to_string(), handler);
+    let _handler = RemoveHandler(name.to_string());
+
+    let mut client = Client::open(vec![&*format!("redis://{}", name)]).unwrap();
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_node_address_mapper(move |address| {
+                    assert_eq!((address.host(), address.port()), ("10.0.0.1", 7000));
+                    NodeAddress::new(name, 6380)
+                })
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6380));
+    let node = runtime.block_on(connection.node_for_key(b"test")).unwrap();
+    assert_eq!(node.as_deref(), Some("10.0.0.1:7000"));
+}
+
+#[test]
+fn transaction_across_slots_is_rejected() {
+    let _ = env_logger::try_init();
+    let name = "transaction_across_slots_is_rejected";
+
+    let requests = Arc::new(atomic::AtomicUsize::new(0));
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], _| {
+            respond_startup(name, cmd)?;
+            requests.fetch_add(1, atomic::Ordering::SeqCst);
+            Err(Ok(Value::Okay))
+        }
+    });
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("SET")
+        .arg("foo")
+        .arg(1)
+        .cmd("SET")
+        .arg("bar")
+        .arg(2);
+    let err = runtime
+        .block_on(pipe.query_async::<_, ()>(&mut connection))
+        .unwrap_err();
+
+    assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 0);
+}
+
+#[test]
+fn two_key_commands_across_slots_are_rejected() {
+    let _ = env_logger::try_init();
+    let name = "two_key_commands_across_slots_are_rejected";
+
+    let requests = Arc::new(atomic::AtomicUsize::new(0));
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            requests.fetch_add(1, atomic::Ordering::SeqCst);
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    // The hash tag puts both keys in the slot of "foo"
+    let port = runtime.block_on(
+        cmd("COPY")
+            .arg("{foo}src")
+            .arg("{foo}dst")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(port, Ok(6380));
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 1);
+
+    let query = |args: &[&str]| {
+        let mut command = redis::Cmd::new();
+        for arg in args {
+            command.arg(*arg);
+        }
+        runtime.block_on(command.query_async::<_, u16>(&mut connection.clone()))
+    };
+    assert_eq!(
+        query(&["LMOVE", "foo", "{foo}bar", "LEFT", "RIGHT"]),
+        Ok(6380)
+    );
+    for args in [
+        &["COPY", "foo", "bar"][..],
+        &["rename", "foo", "bar"],
+        &["SMOVE", "foo", "bar", "member"],
+    ] {
+        let err = query(args).unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+    }
+    // The keys were checked before sending anything
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn eval_is_routed_by_its_declared_keys() {
+    let _ = env_logger::try_init();
+    let name = "eval_is_routed_by_its_declared_keys";
+
+    let requests = Arc::new(atomic::AtomicUsize::new(0));
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            requests.fetch_add(1, atomic::Ordering::SeqCst);
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let query = |args: &[&str]| {
+        let mut command = redis::Cmd::new();
+        for arg in args {
+            command.arg(*arg);
+        }
+        runtime.block_on(command.query_async::<_, u16>(&mut connection.clone()))
+    };
+    // Without keys the arguments are not taken for keys and any node runs the script
+    let port = query(&["EVAL", "return 1", "0", "foo", "bar"]).unwrap();
+    assert!(port == 6379 || port == 6380, "{}", port);
+    // Only the declared keys route the script, not the arguments following them
+    assert_eq!(query(&["EVAL", "return 1", "1", "foo", "bar"]), Ok(6380));
+    assert_eq!(query(&["evalsha", "sha", "1", "bar", "foo"]), Ok(6379));
+    assert_eq!(
+        query(&["EVAL", "return 1", "2", "{foo}1", "{foo}2", "bar"]),
+        Ok(6380)
+    );
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 4);
+
+    for args in [
+        &["EVAL", "return 1", "2", "foo", "bar"][..],
+        &["EVALSHA", "sha", "3", "{foo}1", "{foo}2", "bar"],
+    ] {
+        let err = query(args).unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+    }
+    let err = query(&["EVAL", "return 1", "2", "foo"]).unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+    // The keys were checked before sending anything
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 4);
+}
+
+#[test]
+fn transaction_is_retried_after_moved() {
+    let _ = env_logger::try_init();
+    let name = "transaction_is_retried_after_moved";
+
+    let moved = atomic::AtomicBool::new(false);
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if !moved.load(atomic::Ordering::SeqCst) {
+            respond_startup(name, cmd)?;
+        } else if contains_slice(cmd, b"PING") {
+            return Err(Ok(Value::Status("OK".into())));
+        } else if contains_slice(cmd, b"SLOTS") {
+            // Every slot moved to the node on port 6380
+            return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(16383),
+                Value::Bulk(vec![
+                    Value::Data(name.as_bytes().to_vec()),
+                    Value::Int(6380),
+                ]),
+            ])])));
+        }
+
+        match port {
+            6379 => {
+                moved.store(true, atomic::Ordering::SeqCst);
+                Err(parse_redis_value(
+                    format!("-MOVED 5061 {}:6380\r\n", name).as_bytes(),
+                ))
+            }
+            _ if contains_slice(cmd, b"INCR") => Err(Ok(Value::Int(2))),
+            _ => Err(Ok(Value::Okay)),
+        }
+    });
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("SET")
+        .arg("{bar}.a")
+        .arg(1)
+        .cmd("INCR")
+        .arg("{bar}.b");
+    let value = runtime.block_on(pipe.query_async::<_, (String, i64)>(&mut connection));
+
+    assert_eq!(value, Ok(("OK".to_string(), 2)));
+}
+
+#[test]
+fn script_load_on_all_masters() {
+    let _ = env_logger::try_init();
+    let name = "script_load_on_all_masters";
+
+    let loaded = Arc::new(Mutex::new(HashSet::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let loaded = loaded.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let cmd = &cmd.to_ascii_uppercase()[..];
+            if contains_slice(cmd, b"SCRIPT") && contains_slice(cmd, b"LOAD") {
+                loaded.lock().unwrap().insert(port);
+                let hash = Script::new("return 1").get_hash().to_string();
+                Err(Ok(Value::Data(hash.into_bytes())))
+            } else if contains_slice(cmd, b"EVALSHA") {
+                // The node serving `foo` never keeps the script, as if it were a new master
+                Err(parse_redis_value(b"-NOSCRIPT No matching script\r\n"))
+            } else if contains_slice(cmd, b"EVAL") {
+                Err(Ok(Value::Int(port.into())))
+            } else {
+                panic!("Unexpected command {}", String::from_utf8_lossy(cmd));
+            }
+        }
+    });
+
+    let value = runtime.block_on(
+        Script::new("return 1")
+            .key("foo")
+            .invoke_async::<_, u16>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(6380));
+    assert_eq!(
+        *loaded.lock().unwrap(),
+        vec![6379, 6380].into_iter().collect()
+    );
+
+    // The same in lower case, as redis accepts it
+    loaded.lock().unwrap().clear();
+    let hash = Script::new("return 1").get_hash().to_string();
+    let value = runtime.block_on(
+        cmd("script")
+            .arg("load")
+            .arg("return 1")
+            .query_async::<_, String>(&mut connection),
+    );
+    assert_eq!(value, Ok(hash.clone()));
+    assert_eq!(
+        *loaded.lock().unwrap(),
+        vec![6379, 6380].into_iter().collect()
+    );
+    let value = runtime.block_on(
+        cmd("evalsha")
+            .arg(hash)
+            .arg(1)
+            .arg("foo")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6380));
+}
+
+#[test]
+fn registered_script_is_invoked_by_its_digest() {
+    let _ = env_logger::try_init();
+    let name = "registered_script_is_invoked_by_its_digest";
+
+    let loaded = Arc::new(Mutex::new(HashSet::new()));
+    let loads = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let loaded = loaded.clone();
+        let loads = loads.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let hash = Script::new("return 1").get_hash().to_string();
+            if contains_slice(cmd, b"SCRIPT") && contains_slice(cmd, b"LOAD") {
+                loads.fetch_add(1, atomic::Ordering::SeqCst);
+                loaded.lock().unwrap().insert(port);
+                Err(Ok(Value::Data(hash.into_bytes())))
+            } else if contains_slice(cmd, b"SCRIPT") && contains_slice(cmd, b"FLUSH") {
+                loaded.lock().unwrap().clear();
+                Err(Ok(Value::Okay))
+            } else if contains_slice(cmd, b"EVALSHA") && contains_slice(cmd, hash.as_bytes()) {
+                if loaded.lock().unwrap().contains(&port) {
+                    Err(Ok(Value::Int(port.into())))
+                } else {
+                    Err(parse_redis_value(b"-NOSCRIPT No matching script\r\n"))
+                }
+            } else {
+                panic!("Unexpected command {}", String::from_utf8_lossy(cmd));
+            }
+        }
+    });
+
+    let script = runtime
+        .block_on(connection.register_script("return 1"))
+        .unwrap();
+    assert_eq!(script.hash(), Script::new("return 1").get_hash());
+    assert_eq!(loads.load(atomic::Ordering::SeqCst), 2);
+    for (key, port) in &[("foo", 6380), ("bar", 6379)] {
+        let value = runtime.block_on(script.invoke_async::<_, _, u16>(*key, "arg"));
+        assert_eq!(value, Ok(*port));
+    }
+    assert_eq!(loads.load(atomic::Ordering::SeqCst), 2);
+
+    // Once the cache is flushed the connection no longer knows the script, which is registered
+    // again
+    runtime
+        .block_on(
+            redis::cmd("SCRIPT")
+                .arg("FLUSH")
+                .query_async::<_, ()>(&mut connection),
+        )
+        .unwrap();
+    let value = runtime.block_on(script.invoke_async::<_, _, u16>(&["foo"][..], &[] as &[&str]));
+    assert_eq!(value, Ok(6380));
+    assert_eq!(loads.load(atomic::Ordering::SeqCst), 4);
+}
+
+#[test]
+fn function_load_on_all_masters() {
+    let _ = env_logger::try_init();
+    let name = "function_load_on_all_masters";
+
+    let loaded = Arc::new(Mutex::new(HashSet::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let loaded = loaded.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let upper = &cmd.to_ascii_uppercase()[..];
+            if contains_slice(upper, b"FUNCTION") && contains_slice(upper, b"LOAD") {
+                // The node serving `foo` misses the first load, as if it joined the cluster later
+                if port != 6380 || contains_slice(upper, b"REPLACE") {
+                    loaded.lock().unwrap().insert(port);
+                }
+                Err(Ok(Value::Data(b"mylib".to_vec())))
+            } else if contains_slice(upper, b"FCALL") {
+                if contains_slice(cmd, b"myfunc") && loaded.lock().unwrap().contains(&port) {
+                    Err(Ok(Value::Int(port.into())))
+                } else {
+                    Err(parse_redis_value(b"-ERR Function not found\r\n"))
+                }
+            } else {
+                panic!("Unexpected command {}", String::from_utf8_lossy(cmd));
+            }
+        }
+    });
+
+    let library = "#!lua name=mylib\nredis.register_function('myfunc', function() return 1 end)";
+    // In lower case, as redis accepts it
+    let value = runtime.block_on(
+        cmd("function")
+            .arg("load")
+            .arg(library)
+            .query_async::<_, String>(&mut connection),
+    );
+    assert_eq!(value, Ok("mylib".to_string()));
+    assert_eq!(*loaded.lock().unwrap(), vec![6379].into_iter().collect());
+
+    let mut fcall = |function: &str, key: &str| {
+        let mut fcall = cmd("fcall");
+        fcall.arg(function).arg(1).arg(key);
+        runtime.block_on(fcall.query_async::<_, u16>(&mut connection))
+    };
+    assert_eq!(fcall("myfunc", "bar"), Ok(6379));
+    assert_eq!(fcall("myfunc", "foo"), Ok(6380));
+    assert_eq!(
+        *loaded.lock().unwrap(),
+        vec![6379, 6380].into_iter().collect()
+    );
+
+    let err = fcall("missing", "foo").unwrap_err();
+    assert_eq!(err.detail(), Some("Function not found"));
+}
+
+#[test]
+fn read_from_replica() {
+    let _ = env_logger::try_init();
+    let name = "read_from_replica";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_with_replica(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::PreferReplica)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(read, Ok(6380));
+
+    let write = runtime.block_on(
+        cmd("SET")
+            .arg("foo")
+            .arg("bar")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(write, Ok(6379));
+}
+
+#[test]
+fn masterdown_reads_go_to_another_replica() {
+    let _ = env_logger::try_init();
+    let name = "masterdown_reads_go_to_another_replica";
+
+    // Both replicas lost their master
+    let reads = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let reads = reads.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") || contains_slice(cmd, b"READONLY") {
+                return Err(Ok(Value::Status("OK".into())));
+            }
+            if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+                let node = |port| {
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(port),
+                    ])
+                };
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    node(6379),
+                    node(6380),
+                    node(6381),
+                ])])));
+            }
+            reads.lock().unwrap().push(port);
+            if port == 6379 {
+                return Err(Ok(Value::Int(port.into())));
+            }
+            Err(parse_redis_value(
+                b"-MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.\r\n",
+            ))
+        }
+    });
+
+    // One retry per replica
+    client.set_retries(Some(2));
+    let mut connect = |read_preference, stale_reads| {
+        runtime
+            .block_on(
+                client
+                    .set_read_preference(read_preference)
+                    .set_stale_reads(stale_reads)
+                    .get_generic_connection::<MockConnection>(),
+            )
+            .unwrap()
+    };
+    let mut replicas = connect(ReadPreference::PreferReplica, true);
+    let mut replica_only = connect(ReadPreference::ReplicaOnly, true);
+    let mut no_stale_reads = connect(ReadPreference::PreferReplica, false);
+
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut replicas));
+    assert_eq!(read, Ok(6379));
+    let mut asked = reads.lock().unwrap().split_off(0);
+    asked.sort_unstable();
+    // Each replica was asked once before the master
+    assert_eq!(asked, [6379, 6380, 6381]);
+
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("foo")
+                .query_async::<_, u16>(&mut replica_only),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::ClusterDown);
+    assert!(!reads.lock().unwrap().split_off(0).contains(&6379));
+
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("foo")
+                .query_async::<_, u16>(&mut no_stale_reads),
+        )
+        .unwrap_err();
+    assert_eq!(err.code(), Some("MASTERDOWN"));
+    assert_eq!(reads.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn read_preference_is_overridden_per_connection() {
+    let _ = env_logger::try_init();
+    let name = "read_preference_is_overridden_per_connection";
+
+    let readonly = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        connection,
+        handler: _handler,
+    } = MockEnv::new(name, {
+        let readonly = readonly.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"READONLY") {
+                readonly.lock().unwrap().push(port);
+            }
+            respond_startup_with_replica(name, cmd)?;
+            if contains_slice(cmd, b"MGET") {
+                // One bulk string header per argument
+                let keys = cmd.iter().filter(|&&b| b == b'$').count() - 1;
+                return Err(Ok(Value::Bulk(vec![Value::Int(port.into()); keys])));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    // The client reads from the masters
+    let mut replica = connection.prefer_replica();
+    assert_eq!(replica.read_preference(), ReadPreference::PreferReplica);
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut replica));
+    assert_eq!(read, Ok(6380));
+    // The connection to the replica is not in `READONLY` mode, the read asks for it
+    assert_eq!(*readonly.lock().unwrap(), [6380]);
+    let write = runtime.block_on(
+        cmd("SET")
+            .arg("foo")
+            .arg("bar")
+            .query_async::<_, u16>(&mut replica),
+    );
+    assert_eq!(write, Ok(6379));
+    let read = runtime.block_on(
+        cmd("GET")
+            .arg("foo")
+            .query_async::<_, u16>(&mut connection.clone()),
+    );
+    assert_eq!(read, Ok(6379));
+
+    let replica_reads = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::PreferReplica)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let mut master = replica_reads.to_master();
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut master));
+    assert_eq!(read, Ok(6379));
+    // Split by slot, each part goes to the master
+    let reads = runtime.block_on(
+        cmd("MGET")
+            .arg("foo")
+            .arg("bar")
+            .query_async::<_, Vec<u16>>(&mut master),
+    );
+    assert_eq!(reads, Ok(vec![6379, 6379]));
+    let read = runtime.block_on(
+        cmd("GET")
+            .arg("foo")
+            .query_async::<_, u16>(&mut replica_reads.clone()),
+    );
+    assert_eq!(read, Ok(6380));
+}
+
+#[test]
+fn cluster_shards_leave_out_the_replicas_not_online() {
+    let _ = env_logger::try_init();
+    let name = "cluster_shards_leave_out_the_replicas_not_online";
+
+    let node = |port: i64, role: &str, health: &str| {
+        Value::Bulk(vec![
+            Value::Data(b"port".to_vec()),
+            Value::Int(port),
+            Value::Data(b"endpoint".to_vec()),
+            Value::Data(name.as_bytes().to_vec()),
+            Value::Data(b"role".to_vec()),
+            Value::Data(role.as_bytes().to_vec()),
+            Value::Data(b"health".to_vec()),
+            Value::Data(health.as_bytes().to_vec()),
+        ])
+    };
+    let shards = Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(b"slots".to_vec()),
+        Value::Bulk(vec![Value::Int(0), Value::Int(16383)]),
+        Value::Data(b"nodes".to_vec()),
+        Value::Bulk(vec![
+            node(6379, "master", "online"),
+            node(6380, "replica", "loading"),
+            node(6381, "replica", "online"),
+        ]),
+    ])]);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SHARDS") {
+            return Err(Ok(shards.clone()));
+        }
+        respond_startup_with_replica(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::PreferReplica)
+                .set_cluster_shards(true)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    for _ in 0..8 {
+        let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+        assert_eq!(read, Ok(6381));
+    }
+}
+
+#[test]
+fn cluster_shards_fall_back_to_cluster_slots() {
+    let _ = env_logger::try_init();
+    let name = "cluster_shards_fall_back_to_cluster_slots";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SHARDS") {
+            return Err(parse_redis_value(
+                b"-ERR unknown subcommand 'SHARDS'. Try CLUSTER HELP.\r\n",
+            ));
+        }
+        respond_startup_with_replica(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::PreferReplica)
+                .set_cluster_shards(true)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(read, Ok(6380));
+}
+
+#[test]
+fn recent_read_commands_are_sent_to_the_replica() {
+    let _ = env_logger::try_init();
+    let name = "recent_read_commands_are_sent_to_the_replica";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_with_replica(name, cmd)?;
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::PreferReplica)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let mut query = |cmd: &redis::Cmd| runtime.block_on(cmd.query_async::<_, u16>(&mut connection));
+    assert_eq!(query(cmd("LPOS").arg("foo").arg("a")), Ok(6380));
+    assert_eq!(query(cmd("smismember").arg("foo").arg("a")), Ok(6380));
+    assert_eq!(
+        query(cmd("ZRANGESTORE").arg("{foo}b").arg("foo").arg(0).arg(-1)),
+        Ok(6379)
+    );
+    assert_eq!(query(cmd("MODULE.READ").arg("foo")), Ok(6379));
+}
+
+#[test]
+fn replica_failure_falls_back_to_master() {
+    let _ = env_logger::try_init();
+    let name = "replica_failure_falls_back_to_master";
+
+    let replica_reads = Arc::new(atomic::AtomicUsize::new(0));
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let replica_reads = replica_reads.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_with_replica(name, cmd)?;
+            if port == 6380 {
+                replica_reads.fetch_add(1, atomic::Ordering::SeqCst);
+                return Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                )
+                .into()));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(1))
+                .set_read_preference(ReadPreference::PreferReplica)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(read, Ok(6379));
+    assert_eq!(replica_reads.load(atomic::Ordering::SeqCst), 1);
+
+    // The replica is connected to again for the next read
+    let read = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(read, Ok(6379));
+    assert_eq!(replica_reads.load(atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn replica_redirect_falls_back_to_master() {
+    let _ = env_logger::try_init();
+    let name = "replica_redirect_falls_back_to_master";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_with_replica(name, cmd)?;
+        match port {
+            6380 => Err(parse_redis_value(b"-MOVED 12182 replica_redirect:6379\r\n")),
+            _ => Err(Ok(Value::Int(port.into()))),
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_read_preference(ReadPreference::ReplicaOnly)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let value = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(value, Ok(6379));
+}
+
+#[test]
+fn read_from_sends_the_reads_to_the_replica() {
+    let _ = env_logger::try_init();
+    let name = "read_from_sends_the_reads_to_the_replica";
+
+    let readonly = Arc::new(Mutex::new(Vec::new()));
+    let fail = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let readonly = readonly.clone();
+        let fail = fail.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") {
+                return Err(Ok(Value::Status("OK".into())));
+            }
+            if contains_slice(cmd, b"READONLY") {
+                readonly.lock().unwrap().push(port);
+                return Err(Ok(Value::Status("OK".into())));
+            }
+            if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+                let node = |port| {
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(port),
+                    ])
+                };
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    node(6379),
+                    node(6380),
+                    node(6381),
+                ])])));
+            }
+            if port == 6381 && fail.load(atomic::Ordering::SeqCst) {
+                return Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                )
+                .into()));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let err = runtime
+        .block_on(connection.read_from(&format!("{}:6379", name)))
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.kind(),
+        redis_cluster_async::redis::ErrorKind::InvalidClientConfig
+    );
+
+    let mut replica = runtime
+        .block_on(connection.read_from(&format!("{}:6381", name)))
+        .unwrap();
+    assert_eq!(replica.replica(), format!("{}:6381", name));
+    let mut query = |cmd: &redis::Cmd| runtime.block_on(cmd.query_async::<_, u16>(&mut replica));
+    assert_eq!(query(cmd("GET").arg("foo")), Ok(6381));
+    assert_eq!(query(cmd("SET").arg("foo").arg("bar")), Ok(6379));
+    // The connections to the replicas are not in `READONLY` mode, the reads ask for it
+    assert_eq!(*readonly.lock().unwrap(), [6381]);
+
+    // The master serves the reads the replica fails
+    fail.store(true, atomic::Ordering::SeqCst);
+    assert_eq!(query(cmd("GET").arg("foo")), Ok(6379));
+}
+
+#[test]
+fn response_timeout() {
+    let _ = env_logger::try_init();
+    let name = "response_timeout";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        Err(Ok(Value::Status(STALL.into())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(1))
+                .set_response_timeout(Some(Duration::from_millis(10)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("test")
+                .query_async::<_, Option<i32>>(&mut connection),
+        )
+        .unwrap_err();
+
+    assert_eq!(err.kind(), redis::ErrorKind::IoError);
+    assert!(err.is_timeout());
+}
+
+#[test]
+fn response_timeout_does_not_include_connecting() {
+    let _ = env_logger::try_init();
+    let name = "response_timeout_does_not_include_connecting";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        // Opening the connection for the `ASK` redirection takes longer than the response timeout
+        if port == 6380 && contains_slice(cmd, b"PING") {
+            return Err(Ok(Value::Status(SLOW.into())));
+        }
+        respond_startup(name, cmd)?;
+        match port {
+            6379 => Err(parse_redis_value(
+                format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+            )),
+            _ if contains_slice(cmd, b"ASKING") => Err(Ok(Value::Okay)),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(1))
+                .set_response_timeout(Some(Duration::from_millis(20)))
+                .set_connect_timeout(Some(Duration::from_secs(1)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+}
+
+#[test]
+fn error_classifier_decides_the_retries() {
+    let _ = env_logger::try_init();
+    let name = "error_classifier_decides_the_retries";
+
+    let attempts = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let attempts = attempts.clone();
+        move |cmd: &[u8], _| {
+            respond_startup(name, cmd)?;
+            let attempt = attempts.fetch_add(1, atomic::Ordering::SeqCst);
+            if contains_slice(cmd, b"LOADING") {
+                return Err(parse_redis_value(b"-LOADING Redis is loading\r\n"));
+            }
+            match attempt {
+                0 | 1 => Err(parse_redis_value(b"-OOM command not allowed\r\n")),
+                _ => Err(Ok(Value::Okay)),
+            }
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retry_policy(RetryPolicy::Fixed(Duration::from_millis(1)))
+                .set_error_classifier(|err| match err.code() {
+                    Some("OOM") => RetryDecision::RetryWithBackoff,
+                    Some("LOADING") => RetryDecision::Fail,
+                    _ => RetryDecision::default_for(err),
+                })
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    // `OOM` is retried until memory is freed
+    let value = runtime.block_on(cmd("SET").arg("foo").arg(1).query_async(&mut connection));
+    assert_eq!(value, Ok(Value::Okay));
+    assert_eq!(attempts.load(atomic::Ordering::SeqCst), 3);
+
+    // `LOADING` is returned instead of being waited for
+    attempts.store(0, atomic::Ordering::SeqCst);
+    let err = runtime
+        .block_on(
+            cmd("SET")
+                .arg("LOADING")
+                .arg(1)
+                .query_async::<_, Value>(&mut connection),
+        )
+        .unwrap_err();
+    assert_eq!(err.code(), Some("LOADING"));
+    assert_eq!(attempts.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn connect_timeout_skips_unresponsive_initial_nodes() {
+    let _ = env_logger::try_init();
+    let name = "connect_timeout_skips_unresponsive_initial_nodes";
+
+    let stall_all = Arc::new(atomic::AtomicBool::new(false));
+
+    let MockEnv {
+        runtime,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let stall_all = stall_all.clone();
+        move |cmd: &[u8], port| {
+            // The node on port 6380 never answers the `PING` sent after connecting
+            if contains_slice(cmd, b"PING")
+                && (port == 6380 || stall_all.load(atomic::Ordering::SeqCst))
+            {
+                return Err(Ok(Value::Status(STALL.into())));
+            }
+            respond_startup(name, cmd)?;
+            Err(Ok(Value::Data(b"123".to_vec())))
+        }
+    });
+
+    let mut client = Client::open(vec![
+        &*format!("redis://{}:6380", name),
+        &*format!("redis://{}:6379", name),
+    ])
+    .unwrap();
+    client.set_connect_timeout(Some(Duration::from_millis(10)));
+
+    let started = std::time::Instant::now();
+    let mut connection = runtime
+        .block_on(client.get_generic_connection::<MockConnection>())
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+
+    stall_all.store(true, atomic::Ordering::SeqCst);
+    let err = runtime
+        .block_on(client.get_generic_connection::<MockConnection>())
+        .err()
+        .expect("connection to unresponsive nodes");
+    let detail = err.detail().unwrap_or_default();
+    assert!(detail.contains(&format!("{}:6379", name)), "{}", detail);
+    assert!(detail.contains(&format!("{}:6380", name)), "{}", detail);
+}
+
+#[test]
+fn execute_reports_the_failing_node() {
+    let _ = env_logger::try_init();
+    let name = "execute_reports_the_failing_node";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        if port == 6379 {
+            Err(parse_redis_value(b"-OOM command not allowed\r\n"))
+        } else {
+            Err(Err(std::io::Error::from(
+                std::io::ErrorKind::ConnectionReset,
+            )
+            .into()))
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    // `bar` is served by the node on port 6379 and `foo` by the one on port 6380
+    let err = runtime
+        .block_on(connection.execute::<Option<i32>>(cmd("GET").arg("bar")))
+        .unwrap_err();
+    assert_eq!(err.node(), Some(&*format!("{}:6379", name)));
+    assert_eq!(err.code(), Some("OOM"));
+    assert!(err.to_string().ends_with(&format!("(node {}:6379)", name)));
+
+    let err = runtime
+        .block_on(connection.execute::<Option<i32>>(cmd("GET").arg("foo")))
+        .unwrap_err();
+    assert_eq!(err.node(), Some(&*format!("{}:6380", name)));
+    assert!(err.is_io_error());
+
+    // Converts back to a `RedisError`
+    let result: RedisResult<Option<i32>> = runtime.block_on(async {
+        let value = connection.execute(cmd("GET").arg("bar")).await?;
+        Ok(value)
+    });
+    assert_eq!(result.unwrap_err().code(), Some("OOM"));
+}
+
+#[test]
+fn partial_failures_report_the_result_of_each_part() {
+    let _ = env_logger::try_init();
+    let name = "partial_failures_report_the_result_of_each_part";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        match port {
+            6380 => Err(parse_redis_value(b"-OOM command not allowed\r\n")),
+            _ if contains_slice(cmd, b"MGET") => {
+                Err(Ok(Value::Bulk(vec![Value::Data(b"bar".to_vec())])))
+            }
+            _ => Err(Ok(Value::Okay)),
+        }
+    });
+
+    // `foo` is served by the node on port 6380, which fails, and `bar` by the one on port 6379
+    let err = runtime
+        .block_on(connection.execute::<Vec<String>>(cmd("MGET").arg("foo").arg("bar")))
+        .unwrap_err();
+    assert_eq!(err.code(), Some("OOM"));
+    assert_eq!(err.node(), Some(&*format!("{}:6380", name)));
+    assert!(
+        err.to_string().ends_with("(1 of 2 parts failed)"),
+        "{}",
+        err
+    );
+    let parts = err.partial().unwrap().parts();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].positions(), [0]);
+    assert_eq!(parts[0].node(), Some(&*format!("{}:6380", name)));
+    assert_eq!(parts[0].result().unwrap_err().code(), Some("OOM"));
+    assert_eq!(parts[1].positions(), [1]);
+    assert_eq!(parts[1].node(), Some(&*format!("{}:6379", name)));
+    assert_eq!(
+        parts[1].result().ok(),
+        Some(&Value::Bulk(vec![Value::Data(b"bar".to_vec())]))
+    );
+
+    let err = runtime
+        .block_on(connection.execute::<()>(cmd("SCRIPT").arg("FLUSH")))
+        .unwrap_err();
+    let partial = err.partial().unwrap();
+    let nodes: Vec<_> = partial.parts().iter().map(|part| part.node()).collect();
+    assert_eq!(
+        nodes,
+        [
+            Some(&*format!("{}:6379", name)),
+            Some(&*format!("{}:6380", name))
+        ]
+    );
+    assert_eq!(partial.parts()[0].result().ok(), Some(&Value::Okay));
+    assert_eq!(partial.failed().count(), 1);
+
+    // Converts to the error of the failed part
+    let result = runtime.block_on(
+        cmd("SCRIPT")
+            .arg("FLUSH")
+            .query_async::<_, ()>(&mut connection),
+    );
+    assert_eq!(result.unwrap_err().code(), Some("OOM"));
+}
+
+#[test]
+fn wrongtype_is_not_retried() {
+    let _ = env_logger::try_init();
+    let name = "wrongtype_is_not_retried";
+
+    let requests = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], _| {
+            respond_startup(name, cmd)?;
+            requests.fetch_add(1, atomic::Ordering::SeqCst);
+            Err(parse_redis_value(
+                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            ))
+        }
+    });
+
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("test")
+                .query_async::<_, Option<i32>>(&mut connection),
+        )
+        .unwrap_err();
+
+    assert_eq!(err.code(), Some("WRONGTYPE"));
+    assert_eq!(requests.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn ask_redirect_sends_asking() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_sends_asking";
+
+    let asking = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let asking = asking.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            match port {
+                6379 => Err(parse_redis_value(
+                    format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                _ if contains_slice(cmd, b"ASKING") => {
+                    asking.store(true, atomic::Ordering::SeqCst);
+                    Err(Ok(Value::Okay))
+                }
+                _ => {
+                    assert!(asking.load(atomic::Ordering::SeqCst));
+                    Err(Ok(Value::Data(b"123".to_vec())))
+                }
+            }
+        }
+    });
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
+}
+
+#[test]
+fn ask_redirect_keeps_the_slot_map() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_keeps_the_slot_map";
+
+    let slot_refreshes = Arc::new(atomic::AtomicUsize::new(0));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let slot_refreshes = slot_refreshes.clone();
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"SLOTS") {
+                slot_refreshes.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            respond_startup(name, cmd)?;
+            let asking = contains_slice(cmd, b"ASKING");
+            requests.lock().unwrap().push((port, asking));
+            match port {
+                _ if asking => Err(Ok(Value::Okay)),
+                6379 if contains_slice(cmd, b"migrating") => Err(parse_redis_value(
+                    format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                _ => Err(Ok(Value::Int(port.into()))),
+            }
+        }
+    });
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("migrating")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6380));
+    // `ASKING` is sent right before the command, on the node given by `ASK`
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [(6379, false), (6380, true), (6380, false)]
+    );
+
+    // The redirection only applied to that request, the slot still belongs to the first node
+    requests.lock().unwrap().clear();
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6379));
+    assert_eq!(*requests.lock().unwrap(), [(6379, false)]);
+    assert_eq!(
+        runtime.block_on(connection.node_for_key(b"migrating")),
+        Ok(Some(format!("{}:6379", name)))
+    );
+    assert_eq!(slot_refreshes.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn redirects_are_returned_when_not_followed() {
+    let _ = env_logger::try_init();
+    let name = "redirects_are_returned_when_not_followed";
+
+    let slot_refreshes = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let slot_refreshes = slot_refreshes.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"SLOTS") {
+                slot_refreshes.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            respond_startup(name, cmd)?;
+            match port {
+                6379 if contains_slice(cmd, b"moved") => Err(parse_redis_value(
+                    format!("-MOVED 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                6379 => Err(parse_redis_value(
+                    format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                _ => panic!("The redirection was followed to port {}", port),
+            }
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_follow_redirects(false)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    slot_refreshes.store(0, atomic::Ordering::SeqCst);
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("moved")
+                .query_async::<_, u16>(&mut connection),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::Moved);
+    let err = runtime
+        .block_on(
+            cmd("GET")
+                .arg("asked")
+                .query_async::<_, u16>(&mut connection),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::Ask);
+    assert_eq!(slot_refreshes.load(atomic::Ordering::SeqCst), 0);
+}
+
+#[test]
+fn ask_redirect_is_sent_on_a_connection_of_its_own() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_is_sent_on_a_connection_of_its_own";
+
+    let connects = Arc::new(Mutex::new(HashMap::new()));
+    let asking = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let connects = connects.clone();
+        let asking = asking.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") {
+                *connects.lock().unwrap().entry(port).or_insert(0) += 1;
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            if contains_slice(cmd, b"ASKING") {
+                asking.store(true, atomic::Ordering::SeqCst);
+                return Err(Ok(Value::Okay));
+            }
+            // `ASKING` only ever precedes the redirected command
+            let asked = asking.swap(false, atomic::Ordering::SeqCst);
+            match port {
+                6379 if contains_slice(cmd, b"bar") => Err(parse_redis_value(
+                    format!("-ASK 5061 {}:6380\r\n", name).as_bytes(),
+                )),
+                6380 => {
+                    assert_eq!(asked, contains_slice(cmd, b"bar"));
+                    Err(Ok(Value::Int(port.into())))
+                }
+                _ => Err(Ok(Value::Int(port.into()))),
+            }
+        }
+    });
+
+    let value = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(value, Ok(6380));
+    assert_eq!(connects.lock().unwrap()[&6380], 1);
+
+    let requests = (0..10).map(|i| {
+        let mut connection = connection.clone();
+        let key = if i % 3 == 0 { "bar" } else { "foo" };
+        async move {
+            cmd("GET")
+                .arg(key)
+                .query_async::<_, u16>(&mut connection)
+                .await
+        }
+    });
+    let values = runtime.block_on(future::join_all(requests));
+    assert!(
+        values.iter().all(|value| *value == Ok(6380)),
+        "{:?}",
+        values
+    );
+    // The pooled connection is left to the other requests
+    assert_eq!(connects.lock().unwrap()[&6380], 1 + 4);
+}
+
+#[test]
+fn moved_updates_the_slot_until_the_threshold() {
+    let _ = env_logger::try_init();
+    let name = "moved_updates_the_slot_until_the_threshold";
+
+    let slot_refreshes = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let slot_refreshes = slot_refreshes.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"SLOTS") {
+                slot_refreshes.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            // `foo` (slot 12182) and `qux` (slot 9995) moved to the first node
+            let slot = if contains_slice(cmd, b"foo") {
+                12182
+            } else if contains_slice(cmd, b"qux") {
+                9995
+            } else {
+                return Err(Ok(Value::Int(port.into())));
+            };
+            match port {
+                6380 => Err(parse_redis_value(
+                    format!("-MOVED {} {}:6379\r\n", slot, name).as_bytes(),
+                )),
+                _ => Err(Ok(Value::Int(port.into()))),
+            }
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_moved_refresh_threshold(1)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let refreshes = slot_refreshes.load(atomic::Ordering::SeqCst);
+    let mut get =
+        |key: &str| runtime.block_on(cmd("GET").arg(key).query_async::<_, u16>(&mut connection));
+
+    // Only the slot of `foo` moved, the slot map is not fetched again
+    assert_eq!(get("foo"), Ok(6379));
+    assert_eq!(slot_refreshes.load(atomic::Ordering::SeqCst), refreshes);
+    assert_eq!(get("d"), Ok(6380));
+
+    // The threshold is reached, the slot map is fetched and the count starts over
+    assert_eq!(get("qux"), Ok(6379));
+    assert_eq!(slot_refreshes.load(atomic::Ordering::SeqCst), refreshes + 1);
+    assert_eq!(
+        runtime.block_on(connection.node_for_key(b"qux")),
+        Ok(Some(format!("{}:6379", name)))
+    );
+}
+
+#[test]
+fn missing_slots_are_fetched_again() {
+    let _ = env_logger::try_init();
+    let name = "missing_slots_are_fetched_again";
+
+    let slot_requests = Arc::new(atomic::AtomicUsize::new(0));
+    let redirected = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let slot_requests = slot_requests.clone();
+        let redirected = redirected.clone();
+        move |cmd: &[u8], port| {
+            // The first slot map misses the slots of the second node
+            if contains_slice(cmd, b"SLOTS")
+                && slot_requests.fetch_add(1, atomic::Ordering::SeqCst) == 0
+            {
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(8191),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6379),
+                    ]),
+                ])])));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            match port {
+                6379 => {
+                    redirected.fetch_add(1, atomic::Ordering::SeqCst);
+                    Err(parse_redis_value(
+                        format!("-MOVED 12182 {}:6380\r\n", name).as_bytes(),
+                    ))
+                }
+                _ => Err(Ok(Value::Int(port.into()))),
+            }
+        }
+    });
+
+    for _ in 0..2 {
+        let port = runtime
+            .block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection))
+            .unwrap();
+        assert_eq!(port, 6380);
+    }
+    // Only the first command was sent to a random node
+    assert_eq!(redirected.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn redirect_observer_sees_each_redirection() {
+    let _ = env_logger::try_init();
+    let name = "redirect_observer_sees_each_redirection";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup(name, cmd)?;
+        match port {
+            // The importing node is given without its host, which is the one of this node
+            6379 => Err(parse_redis_value(b"-ASK 123 :6380\r\n")),
+            _ => Err(Ok(Value::Int(port.into()))),
+        }
+    });
+
+    let redirects = Arc::new(Mutex::new(Vec::new()));
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_redirect_observer({
+                    let redirects = redirects.clone();
+                    move |redirect| redirects.lock().unwrap().push(redirect.clone())
+                })
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6380));
+
+    let redirects = redirects.lock().unwrap();
+    assert_eq!(redirects.len(), 1);
+    assert_eq!(redirects[0].kind(), RedirectKind::Ask);
+    assert_eq!(redirects[0].slot(), 123);
+    assert_eq!(redirects[0].addr(), format!("{}:6380", name));
+}
+
+#[test]
+fn ask_redirect_of_a_pipeline_prefixes_each_command() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_of_a_pipeline_prefixes_each_command";
+
+    let asking = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let asking = asking.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            match port {
+                6379 => Err(parse_redis_value(
+                    format!("-ASK 123 {}:6380\r\n", name).as_bytes(),
+                )),
+                _ if contains_slice(cmd, b"ASKING") => {
+                    asking.store(true, atomic::Ordering::SeqCst);
+                    Err(Ok(Value::Okay))
+                }
+                // The flag only applies to the next command
+                _ if asking.swap(false, atomic::Ordering::SeqCst) => {
+                    Err(Ok(Value::Data(b"123".to_vec())))
+                }
+                _ => Err(parse_redis_value(
+                    format!("-MOVED 123 {}:6379\r\n", name).as_bytes(),
+                )),
+            }
+        }
+    });
+
+    let values = runtime.block_on(
+        redis::pipe()
+            .get("{test}a")
+            .get("{test}b")
+            .query_async::<_, Vec<i32>>(&mut connection),
+    );
+    assert_eq!(values, Ok(vec![123, 123]));
+}
+
+#[test]
+fn ask_redirect_of_a_split_multi_key_command() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_of_a_split_multi_key_command";
+
+    // The slot of "{bar}" is migrating from 6379 to 6380, which does not have all its keys yet
+    let asking = Arc::new(atomic::AtomicBool::new(false));
+    let imports = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let asking = asking.clone();
+        let imports = imports.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            match port {
+                6379 => {
+                    assert!(contains_slice(cmd, b"{bar}"));
+                    Err(parse_redis_value(
+                        format!("-ASK 5061 {}:6380\r\n", name).as_bytes(),
+                    ))
+                }
+                _ if contains_slice(cmd, b"ASKING") => {
+                    asking.store(true, atomic::Ordering::SeqCst);
+                    Err(Ok(Value::Okay))
+                }
+                _ if contains_slice(cmd, b"{bar}") => {
+                    assert!(asking.swap(false, atomic::Ordering::SeqCst));
+                    if imports.fetch_add(1, atomic::Ordering::SeqCst) == 0 {
+                        return Err(parse_redis_value(
+                            b"-TRYAGAIN Multiple keys request during rehashing of slot\r\n",
+                        ));
+                    }
+                    Err(Ok(Value::Bulk(vec![
+                        Value::Data(b"1".to_vec()),
+                        Value::Data(b"2".to_vec()),
+                    ])))
+                }
+                _ => Err(Ok(Value::Bulk(vec![Value::Data(b"3".to_vec())]))),
+            }
+        }
+    });
+
+    let values = runtime.block_on(
+        cmd("MGET")
+            .arg("{bar}a")
+            .arg("foo")
+            .arg("{bar}b")
+            .query_async::<_, Vec<i32>>(&mut connection),
+    );
+    assert_eq!(values, Ok(vec![1, 3, 2]));
+    // Both keys of the migrating slot were asked together, again once the first try failed
+    assert_eq!(imports.load(atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn ask_redirect_from_the_importing_node() {
+    let _ = env_logger::try_init();
+    let name = "ask_redirect_from_the_importing_node";
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let requests = requests.clone();
+        move |cmd: &[u8], port| {
+            respond_startup(name, cmd)?;
+            let asking = contains_slice(cmd, b"ASKING");
+            requests.lock().unwrap().push((port, asking));
+            match port {
+                _ if asking => Err(Ok(Value::Okay)),
+                6379 | 6380 => Err(parse_redis_value(
+                    format!("-ASK 123 {}:{}\r\n", name, port + 1).as_bytes(),
+                )),
+                _ => Err(Ok(Value::Int(port.into()))),
+            }
+        }
+    });
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, u16>(&mut connection),
+    );
+    assert_eq!(value, Ok(6381));
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [
+            (6379, false),
+            (6380, true),
+            (6380, false),
+            (6381, true),
+            (6381, false)
+        ]
+    );
+}
+
+// A scripted cluster for the tests about routing and redirections. Its nodes, all reached under
+// the name of the test, serve the slots assigned to them and redirect the commands for the other
+// slots with `MOVED`, or with `ASK` while a slot migrates. Commands are routed by their first
+// argument, the nodes which are taken down fail every command with an I/O error. Each node
+// records the commands it received so the tests can check where they were sent.
+#[derive(Clone)]
+struct MockCluster {
+    name: String,
+    state: Arc<Mutex<MockClusterState>>,
+}
+
+#[derive(Default)]
+struct MockClusterState {
+    // The first and last slot of each range and the port of its master
+    slots: Vec<(u16, u16, u16)>,
+    // The slots being migrated and the port of the node importing them
+    migrating: HashMap<u16, u16>,
+    down: HashSet<u16>,
+    received: Vec<(u16, Vec<Vec<u8>>)>,
+}
+
+impl MockCluster {
+    fn new(name: &str) -> Self {
+        MockCluster {
+            name: name.to_string(),
+            state: Default::default(),
+        }
+    }
+
+    // Serve `slots` from the node on `port`, completing their migration if any
+    fn assign(&self, slots: RangeInclusive<u16>, port: u16) -> &Self {
+        let (start, end) = (*slots.start(), *slots.end());
+        let mut state = self.state.lock().unwrap();
+        let mut ranges = Vec::new();
+        for (first, last, owner) in state.slots.drain(..) {
+            if first < start {
+                ranges.push((first, last.min(start - 1), owner));
+            }
+            if last > end {
+                ranges.push((first.max(end + 1), last, owner));
+            }
+        }
+        ranges.push((start, end, port));
+        ranges.sort_unstable();
+        state.slots = ranges;
+        state.migrating.retain(|slot, _| !slots.contains(slot));
+        self
+    }
+
+    // Start migrating `slot` to the node on `port`
+    fn migrate(&self, slot: u16, port: u16) -> &Self {
+        self.state.lock().unwrap().migrating.insert(slot, port);
+        self
+    }
+
+    fn set_down(&self, port: u16, down: bool) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        if down {
+            state.down.insert(port);
+        } else {
+            state.down.remove(&port);
+        }
+        self
+    }
+
+    // The commands received since the last call, with the port of the node they were sent to.
+    // The `PING`, `READONLY` and `CLUSTER` commands the client sends to manage its connections
+    // are left out.
+    fn received(&self) -> Vec<(u16, String)> {
+        self.state
+            .lock()
+            .unwrap()
+            .received
+            .drain(..)
+            .filter(|(_, args)| {
+                !matches!(
+                    &args[0].to_ascii_uppercase()[..],
+                    b"PING" | b"READONLY" | b"CLUSTER"
+                )
+            })
+            .map(|(port, args)| {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg))
+                    .collect();
+                (port, args.join(" "))
+            })
+            .collect()
+    }
+
+    // The handler of the nodes for `MockEnv::new`, the commands served by a node get the
+    // response of `respond`
+    fn handler(
+        &self,
+        respond: impl Fn(&[Vec<u8>], u16) -> RedisResult<Value> + Send + Sync + 'static,
+    ) -> impl Fn(&[u8], u16) -> Result<(), RedisResult<Value>> + Send + Sync + 'static {
+        let cluster = self.clone();
+        move |cmd, port| {
+            let args = match parse_redis_value(cmd).unwrap() {
+                Value::Bulk(args) => args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        Value::Data(arg) => arg,
+                        arg => panic!("Unexpected argument {:?}", arg),
+                    })
+                    .collect::<Vec<_>>(),
+                cmd => panic!("Unexpected command {:?}", cmd),
+            };
+            cluster.serve(&args, port)?;
+            Err(respond(&args, port))
+        }
+    }
+
+    // Respond to the commands about the cluster and redirect those for the slots the node does
+    // not serve, `Ok` if the node serves the command
+    fn serve(&self, args: &[Vec<u8>], port: u16) -> Result<(), RedisResult<Value>> {
+        let mut state = self.state.lock().unwrap();
+        if state.down.contains(&port) {
+            return Err(Err(io::Error::from(io::ErrorKind::ConnectionReset).into()));
+        }
+        let previous = state
+            .received
+            .iter()
+            .rev()
+            .find(|(previous_port, _)| *previous_port == port);
+        let asking =
+            matches!(previous, Some((_, previous)) if previous[0].eq_ignore_ascii_case(b"ASKING"));
+        state.received.push((port, args.to_vec()));
+
+        match &args[0].to_ascii_uppercase()[..] {
+            b"PING" | b"READONLY" | b"ASKING" => return Err(Ok(Value::Status("OK".into()))),
+            b"CLUSTER" if args[1].eq_ignore_ascii_case(b"SLOTS") => {
+                let slots = state
+                    .slots
+                    .iter()
+                    .map(|(first, last, owner)| {
+                        Value::Bulk(vec![
+                            Value::Int((*first).into()),
+                            Value::Int((*last).into()),
+                            Value::Bulk(vec![
+                                Value::Data(self.name.as_bytes().to_vec()),
+                                Value::Int((*owner).into()),
+                            ]),
+                        ])
+                    })
+                    .collect();
+                return Err(Ok(Value::Bulk(slots)));
+            }
+            _ => (),
+        }
+
+        let key = match &args[0].to_ascii_uppercase()[..] {
+            b"XREAD" | b"XREADGROUP" => args
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case(b"STREAMS"))
+                .and_then(|position| args.get(position + 1)),
+            b"XGROUP" => args.get(2),
+            _ => args.get(1),
+        };
+        let slot = match key {
+            Some(key) => Client::get_slot_for_key(key),
+            None => return Ok(()),
+        };
+        let owner = state
+            .slots
+            .iter()
+            .find(|(first, last, _)| (*first..=*last).contains(&slot))
+            .map(|(_, _, owner)| *owner)
+            .expect("Slot is not assigned");
+        let (kind, target) = match state.migrating.get(&slot) {
+            Some(&target) if port == owner => ("ASK", target),
+            Some(&target) if port == target && asking => return Ok(()),
+            _ if port == owner => return Ok(()),
+            _ => ("MOVED", owner),
+        };
+        Err(parse_redis_value(
+            format!("-{} {} {}:{}\r\n", kind, slot, self.name, target).as_bytes(),
+        ))
+    }
+}
+
+fn respond_port(_: &[Vec<u8>], port: u16) -> RedisResult<Value> {
+    Ok(Value::Int(port.into()))
+}
+
+#[test]
+fn mock_cluster_routes_by_slot() {
+    let _ = env_logger::try_init();
+    let name = "mock_cluster_routes_by_slot";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, cluster.handler(respond_port));
+    cluster.received();
+
+    for key in &["foo", "bar", "{foo}bar"] {
+        runtime
+            .block_on(cmd("GET").arg(*key).query_async::<_, u16>(&mut connection))
+            .unwrap();
+    }
+    assert_eq!(
+        cluster.received(),
+        [
+            (6380, "GET foo".into()),
+            (6379, "GET bar".into()),
+            (6380, "GET {foo}bar".into())
+        ]
+    );
+}
+
+#[test]
+fn mock_cluster_follows_slot_migration() {
+    let _ = env_logger::try_init();
+    let name = "mock_cluster_follows_slot_migration";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, cluster.handler(respond_port));
+    let slot = Client::get_slot_for_key(b"foo");
+    let mut get_foo = || {
+        runtime
+            .block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection))
+            .unwrap()
+    };
+
+    // While the slot migrates the command is redirected each time without updating the slots
+    cluster.migrate(slot, 6381);
+    cluster.received();
+    assert_eq!(get_foo(), 6381);
+    assert_eq!(get_foo(), 6381);
+    assert_eq!(
+        cluster.received(),
+        [
+            (6380, "GET foo".into()),
+            (6381, "ASKING".into()),
+            (6381, "GET foo".into()),
+            (6380, "GET foo".into()),
+            (6381, "ASKING".into()),
+            (6381, "GET foo".into())
+        ]
+    );
+
+    // Once migrated `MOVED` sends the following commands to the new node directly
+    cluster.assign(slot..=slot, 6381);
+    assert_eq!(get_foo(), 6381);
+    assert_eq!(get_foo(), 6381);
+    assert_eq!(
+        cluster.received(),
+        [
+            (6380, "GET foo".into()),
+            (6381, "GET foo".into()),
+            (6381, "GET foo".into())
+        ]
+    );
+}
+
+#[test]
+fn mock_cluster_fails_over_to_the_new_master() {
+    let _ = env_logger::try_init();
+    let name = "mock_cluster_fails_over_to_the_new_master";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, cluster.handler(respond_port));
+
+    cluster.set_down(6380, true).assign(8192..=16383, 6381);
+    cluster.received();
+    let port = runtime
+        .block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection))
+        .unwrap();
+    assert_eq!(port, 6381);
+    assert_eq!(cluster.received().last(), Some(&(6381, "GET foo".into())));
+}
+
+#[test]
+fn readonly_error_refreshes_the_slots() {
+    let _ = env_logger::try_init();
+    let name = "readonly_error_refreshes_the_slots";
+
+    let failed_over = Arc::new(atomic::AtomicBool::new(false));
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let failed_over = failed_over.clone();
+        let writes = writes.clone();
+        move |cmd: &[u8], port| {
+            let master = if failed_over.load(atomic::Ordering::SeqCst) {
+                6380
+            } else {
+                6379
+            };
+            if contains_slice(cmd, b"PING") {
+                return Err(Ok(Value::Status("OK".into())));
+            } else if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(master),
+                    ]),
+                ])])));
+            }
+            writes.lock().unwrap().push(port);
+            if port == 6379 && master != 6379 {
+                // The old master still answers but has become a replica of the new one
+                return Err(parse_redis_value(
+                    b"-READONLY You can't write against a read only replica.\r\n",
+                ));
+            }
+            Err(Ok(Value::Okay))
+        }
+    });
+
+    failed_over.store(true, atomic::Ordering::SeqCst);
+    let value = runtime.block_on(
+        cmd("SET")
+            .arg("foo")
+            .arg("bar")
+            .query_async::<_, ()>(&mut connection),
+    );
+    assert_eq!(value, Ok(()));
+    assert_eq!(*writes.lock().unwrap(), [6379, 6380]);
+}
+
+// The reply of `XREADGROUP` with the entries `ids` of the stream `key`, each holding `f`
+fn stream_reply(key: &[u8], ids: &[&str]) -> Value {
+    let entries = ids
+        .iter()
+        .map(|id| {
+            Value::Bulk(vec![
+                Value::Data(id.as_bytes().to_vec()),
+                Value::Bulk(vec![
+                    Value::Data(b"f".to_vec()),
+                    Value::Data(id.as_bytes().to_vec()),
+                ]),
+            ])
+        })
+        .collect();
+    Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(key.to_vec()),
+        Value::Bulk(entries),
+    ])])
+}
+
+#[test]
+fn xread_group_stream_follows_a_failover() {
+    let _ = env_logger::try_init();
+    let name = "xread_group_stream_follows_a_failover";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let reads = Arc::new(Mutex::new(HashMap::<u16, usize>::new()));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let cluster = cluster.clone();
+        let reads = reads.clone();
+        cluster.clone().handler(move |args, port| {
+            if !args[0].eq_ignore_ascii_case(b"XREADGROUP") {
+                return Ok(Value::Int(1));
+            }
+            let key = &args[args.len() - 2];
+            let id = &args[args.len() - 1][..];
+            let mut reads = reads.lock().unwrap();
+            let read = reads.entry(port).or_default();
+            *read += 1;
+            match (port, id, *read) {
+                (6379, b">", 1) => Ok(stream_reply(key, &["1-1"])),
+                (6380, b">", 1) => Ok(stream_reply(key, &["2-1"])),
+                (6380, b">", _) => {
+                    // The master of `foo` fails while the consumer waits for entries
+                    cluster.set_down(6380, true).assign(8192..=16383, 6381);
+                    Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+                }
+                // No entry was delivered to the consumer after `2-1`
+                (6381, b"2-1", _) => Ok(stream_reply(key, &[])),
+                (6381, b">", 2) => Ok(stream_reply(key, &["2-2"])),
+                _ => Ok(Value::Status(STALL.into())),
+            }
+        })
+    });
+    cluster.received();
+
+    let options = StreamReadOptions::new().with_block(Duration::from_millis(10));
+    let entries = runtime.block_on(
+        connection
+            .xread_group_stream("group", "consumer", &["foo", "bar"], options)
+            .take(3)
+            .collect::<Vec<_>>(),
+    );
+    let mut entries = entries
+        .into_iter()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            assert_eq!(
+                entry.fields(),
+                [("f".into(), Value::Data(entry.id().into()))]
+            );
+            (entry.key().to_vec(), entry.id().to_string())
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            ("bar".into(), "1-1".into()),
+            ("foo".into(), "2-1".into()),
+            ("foo".into(), "2-2".into())
+        ]
+    );
+
+    let reads: Vec<_> = cluster
+        .received()
+        .into_iter()
+        .filter(|(port, cmd)| *port == 6381 && cmd.starts_with("XREADGROUP"))
+        .map(|(_, cmd)| cmd)
+        .collect();
+    assert_eq!(
+        reads[..2],
+        [
+            "XREADGROUP GROUP group consumer STREAMS foo 2-1",
+            "XREADGROUP GROUP group consumer BLOCK 10 STREAMS foo >"
+        ]
+    );
+}
+
+#[test]
+fn xread_group_stream_reads_the_pending_entries_after_a_lost_reply() {
+    let _ = env_logger::try_init();
+    let name = "xread_group_stream_reads_the_pending_entries_after_a_lost_reply";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=16383, 6379);
+    let reads = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let reads = reads.clone();
+        cluster.clone().handler(move |args, _| {
+            if !args[0].eq_ignore_ascii_case(b"XREADGROUP") {
+                return Ok(Value::Int(1));
+            }
+            let key = &args[args.len() - 2];
+            let id = &args[args.len() - 1][..];
+            match (id, reads.fetch_add(1, atomic::Ordering::SeqCst)) {
+                // `1-1` is delivered to the consumer but the reply is cut off
+                (b">", 0) => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+                (b"0", _) => Ok(stream_reply(key, &["1-1"])),
+                (b"1-1", _) => Ok(stream_reply(key, &[])),
+                (b">", _) => Ok(stream_reply(key, &["1-2"])),
+                _ => Ok(Value::Status(STALL.into())),
+            }
+        })
+    });
+    cluster.received();
+
+    let options = StreamReadOptions::new().with_block(Duration::from_millis(10));
+    let entries = runtime.block_on(
+        connection
+            .xread_group_stream("group", "consumer", &["foo"], options)
+            .take(2)
+            .collect::<Vec<_>>(),
+    );
+    let entries = entries
+        .into_iter()
+        .map(|entry| entry.unwrap().id().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(entries, ["1-1", "1-2"]);
+
+    let reads: Vec<_> = cluster
+        .received()
+        .into_iter()
+        .filter(|(_, cmd)| cmd.starts_with("XREADGROUP"))
+        .map(|(_, cmd)| cmd)
+        .collect();
+    assert_eq!(
+        reads[..2],
+        [
+            "XREADGROUP GROUP group consumer BLOCK 10 STREAMS foo >",
+            "XREADGROUP GROUP group consumer STREAMS foo 0"
+        ]
+    );
+}
+
+#[test]
+fn reconnect_policy_holds_back_reconnects() {
+    let _ = env_logger::try_init();
+    let name = "reconnect_policy_holds_back_reconnects";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let attempts = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let handler = cluster.handler(respond_port);
+        let attempts = attempts.clone();
+        move |cmd: &[u8], port| {
+            if port == 6380 {
+                attempts.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            handler(cmd, port)
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .set_reconnect_policy(Some(RetryPolicy::Fixed(Duration::from_secs(3600))))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let mut get =
+        |key: &str| runtime.block_on(cmd("GET").arg(key).query_async::<_, u16>(&mut connection));
+    assert_eq!(get("foo"), Ok(6380));
+
+    cluster.set_down(6380, true);
+    attempts.store(0, atomic::Ordering::SeqCst);
+    // The command breaks the connection, the next one fails to connect again and the following
+    // ones fail without reaching the node
+    for _ in 0..4 {
+        assert!(get("foo").unwrap_err().is_io_error());
+    }
+    assert_eq!(attempts.load(atomic::Ordering::SeqCst), 3);
+    assert_eq!(get("bar"), Ok(6379));
+}
+
+#[test]
+fn connected_shard_count_leaves_out_the_nodes_backing_off() {
+    let _ = env_logger::try_init();
+    let name = "connected_shard_count_leaves_out_the_nodes_backing_off";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, cluster.handler(respond_port));
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .set_reconnect_policy(Some(RetryPolicy::Fixed(Duration::from_secs(3600))))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let probe = connection.clone();
+    let count = || runtime.block_on(probe.connected_shard_count()).unwrap();
+    assert_eq!(count(), (2, 2));
+
+    cluster.set_down(6380, true);
+    // The command breaks the connection
+    let get = cmd("GET").arg("foo").clone();
+    let result = runtime.block_on(get.query_async::<_, u16>(&mut connection));
+    assert!(result.unwrap_err().is_io_error());
+    assert_eq!(count(), (1, 2));
+    // Reconnecting fails, the node is then held back
+    let result = runtime.block_on(get.query_async::<_, u16>(&mut connection));
+    assert!(result.unwrap_err().is_io_error());
+    assert_eq!(count(), (1, 2));
+}
+
+#[test]
+fn keyless_commands_avoid_the_masters_waiting_to_reconnect() {
+    let _ = env_logger::try_init();
+    let name = "keyless_commands_avoid_the_masters_waiting_to_reconnect";
+
+    let cluster = MockCluster::new(name);
+    cluster.assign(0..=8191, 6379).assign(8192..=16383, 6380);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, cluster.handler(respond_port));
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .set_reconnect_policy(Some(RetryPolicy::Fixed(Duration::from_secs(3600))))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let mut ports = HashSet::new();
+    for _ in 0..32 {
+        let port = runtime.block_on(cmd("TIME").query_async::<_, u16>(&mut connection));
+        ports.insert(port.unwrap());
+    }
+    // Spread over the masters
+    assert_eq!(ports, [6379, 6380].iter().copied().collect());
+
+    cluster.set_down(6380, true);
+    for _ in 0..2 {
+        let result = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+        assert!(result.unwrap_err().is_io_error());
+    }
+    for _ in 0..32 {
+        let port = runtime.block_on(cmd("TIME").query_async::<_, u16>(&mut connection));
+        assert_eq!(port, Ok(6379));
+    }
+}
+
+#[test]
+fn fixed_retry_policy() {
+    let _ = env_logger::try_init();
+    let name = "fixed_retry_policy";
+
+    let requests = atomic::AtomicUsize::new(0);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+
+        match requests.fetch_add(1, atomic::Ordering::SeqCst) {
+            0..=4 => Err(parse_redis_value(b"-CLUSTERDOWN mock\r\n")),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retry_policy(RetryPolicy::Fixed(Duration::from_millis(1)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[derive(Default)]
+struct RecordedMetrics {
+    moved: Mutex<Vec<String>>,
+    retries: Mutex<Vec<(String, String)>>,
+    refreshes: atomic::AtomicUsize,
+}
+
+impl ClusterMetrics for RecordedMetrics {
+    fn on_moved(&self, node: &str) {
+        self.moved.lock().unwrap().push(node.to_string());
+    }
+
+    fn on_retry(&self, node: &str, error: &redis::RedisError) {
+        let code = error.code().unwrap_or_default().to_string();
+        self.retries.lock().unwrap().push((node.to_string(), code));
+    }
+
+    fn on_topology_refresh(&self, _duration: Duration) {
+        self.refreshes.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn metrics_report_redirections_and_retries() {
+    let _ = env_logger::try_init();
+    let name = "metrics_report_redirections_and_retries";
+
+    let moved = atomic::AtomicBool::new(false);
+    let requests = atomic::AtomicUsize::new(0);
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if !moved.load(atomic::Ordering::SeqCst) {
+            respond_startup(name, cmd)?;
+        } else if contains_slice(cmd, b"PING") {
+            return Err(Ok(Value::Status("OK".into())));
+        } else if contains_slice(cmd, b"SLOTS") {
+            return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(16383),
+                Value::Bulk(vec![
+                    Value::Data(name.as_bytes().to_vec()),
+                    Value::Int(6380),
+                ]),
+            ])])));
+        }
+
+        if port == 6379 {
+            moved.store(true, atomic::Ordering::SeqCst);
+            return Err(parse_redis_value(
+                format!("-MOVED 6918 {}:6380\r\n", name).as_bytes(),
+            ));
+        }
+        match requests.fetch_add(1, atomic::Ordering::SeqCst) {
+            0 => Err(parse_redis_value(b"-TRYAGAIN mock\r\n")),
+            _ => Err(Ok(Value::Data(b"123".to_vec()))),
+        }
+    });
+
+    let metrics = Arc::new(RecordedMetrics::default());
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_retry_policy(RetryPolicy::Fixed(Duration::from_millis(1)))
+                .set_metrics_handler(metrics.clone())
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    assert_eq!(metrics.refreshes.load(atomic::Ordering::SeqCst), 1);
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+
+    assert_eq!(value, Ok(Some(123)));
+    let node = |port| format!("{}:{}", name, port);
+    assert_eq!(*metrics.moved.lock().unwrap(), vec![node(6380)]);
+    assert_eq!(
+        *metrics.retries.lock().unwrap(),
+        vec![
+            (node(6379), "MOVED".to_string()),
+            (node(6380), "TRYAGAIN".to_string())
+        ]
+    );
+    assert_eq!(metrics.refreshes.load(atomic::Ordering::SeqCst), 2);
+}
+
+// Respond to the multi-key commands with the port of the node for every key (one `MGET` value or
+// one deleted key per key)
+fn respond_multi_key(cmd: &[u8], port: u16) -> Result<(), RedisResult<Value>> {
+    let args = match parse_redis_value(cmd) {
+        Ok(Value::Bulk(args)) => args,
+        _ => panic!("Invalid command"),
+    };
+    match &args[0] {
+        Value::Data(name) if name.eq_ignore_ascii_case(b"MGET") => Err(Ok(Value::Bulk(
+            args[1..].iter().map(|_| Value::Int(port.into())).collect(),
+        ))),
+        Value::Data(name) if name.eq_ignore_ascii_case(b"MSET") => Err(Ok(Value::Okay)),
+        _ => Err(Ok(Value::Int(args.len() as i64 - 1))),
+    }
+}
+
+#[test]
+fn multi_key_commands_split_by_slot() {
+    let _ = env_logger::try_init();
+    let name = "multi_key_commands_split_by_slot";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        respond_multi_key(cmd, port)
+    });
+
+    // `foo` is in slot 12182 and `bar` in slot 5061
+    let value = runtime.block_on(
+        cmd("MGET")
+            .arg("foo")
+            .arg("bar")
+            .arg("foo")
+            .arg("{bar}baz")
+            .query_async::<_, Vec<u16>>(&mut connection),
+    );
+    assert_eq!(value, Ok(vec![6380, 6379, 6380, 6379]));
+
+    let value = runtime.block_on(
+        cmd("MSET")
+            .arg("foo")
+            .arg(1)
+            .arg("bar")
+            .arg(2)
+            .query_async::<_, String>(&mut connection),
+    );
+    assert_eq!(value, Ok("OK".to_string()));
+
+    let value = runtime.block_on(
+        cmd("DEL")
+            .arg("foo")
+            .arg("bar")
+            .arg("{bar}baz")
+            .query_async::<_, i64>(&mut connection),
+    );
+    assert_eq!(value, Ok(3));
+
+    // In lower case, as redis accepts it
+    let value = runtime.block_on(
+        cmd("mget")
+            .arg("foo")
+            .arg("bar")
+            .query_async::<_, Vec<u16>>(&mut connection),
+    );
+    assert_eq!(value, Ok(vec![6380, 6379]));
+}
+
+#[test]
+fn multi_key_commands_split_disabled() {
+    let _ = env_logger::try_init();
+    let name = "multi_key_commands_split_disabled";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup_two_nodes(name, cmd)?;
+        Err(parse_redis_value(
+            b"-CROSSSLOT Keys in request don't hash to the same slot\r\n",
+        ))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_split_multi_key_commands(false)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    let err = runtime
+        .block_on(
+            cmd("MGET")
+                .arg("foo")
+                .arg("bar")
+                .query_async::<_, Vec<u16>>(&mut connection),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+}
+
+#[test]
+fn periodic_topology_refresh() {
+    let _ = env_logger::try_init();
+    let name = "periodic_topology_refresh";
+
+    let slot_requests = atomic::AtomicUsize::new(0);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if contains_slice(cmd, b"PING") {
+            return Err(Ok(Value::Status("OK".into())));
+        }
+        if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+            // Every slot moves to 6380 after the first refresh
+            let port = match slot_requests.fetch_add(1, atomic::Ordering::SeqCst) {
+                0..=1 => 6379,
+                _ => 6380,
+            };
+            return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(16383),
+                Value::Bulk(vec![
+                    Value::Data(name.as_bytes().to_vec()),
+                    Value::Int(port),
+                ]),
+            ])])));
+        }
+        // The client must not need a redirection to find the new node
+        assert_eq!(port, 6380);
+        Err(Ok(Value::Data(b"123".to_vec())))
+    });
+
+    let mut connection = runtime
+        .block_on(
+            client
+                .set_topology_refresh_interval(Some(Duration::from_millis(10)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    // Wake the connection up so the refresh timer is started
+    runtime
+        .block_on(cmd("PING").query_async::<_, String>(&mut connection))
+        .unwrap();
+    runtime.block_on(async { tokio::time::sleep(Duration::from_millis(100)).await });
+
+    let value = runtime.block_on(
+        cmd("GET")
+            .arg("test")
+            .query_async::<_, Option<i32>>(&mut connection),
+    );
+    assert_eq!(value, Ok(Some(123)));
+}
+
+#[test]
+fn refresh_topology_swaps_in_the_new_slot_map() {
+    let _ = env_logger::try_init();
+    let name = "refresh_topology_swaps_in_the_new_slot_map";
+
+    // 0: every slot is served by 6379, 1: by 6380, 2: `CLUSTER SLOTS` fails
+    let state = Arc::new(atomic::AtomicUsize::new(0));
+    let slot_requests = Arc::new(atomic::AtomicUsize::new(0));
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let state = state.clone();
+        let slot_requests = slot_requests.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") {
+                return Err(Ok(Value::Status("OK".into())));
+            }
+            if contains_slice(cmd, b"CLUSTER") && contains_slice(cmd, b"SLOTS") {
+                slot_requests.fetch_add(1, atomic::Ordering::SeqCst);
+                let port = match state.load(atomic::Ordering::SeqCst) {
+                    0 => 6379,
+                    1 => 6380,
+                    _ => return Err(parse_redis_value(b"-ERR boom\r\n")),
+                };
+                return Err(Ok(Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(port),
+                    ]),
+                ])])));
+            }
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    state.store(1, atomic::Ordering::SeqCst);
+    let slot_requests_before = slot_requests.load(atomic::Ordering::SeqCst);
+    let (first, second) = runtime.block_on(future::join(
+        connection.refresh_topology(),
+        connection.refresh_topology(),
+    ));
+    assert_eq!((first, second), (Ok(()), Ok(())));
+    // The two calls shared the same fetch
+    assert_eq!(
+        slot_requests.load(atomic::Ordering::SeqCst),
+        slot_requests_before + 1
+    );
+    let port = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(port, Ok(6380));
+
+    state.store(2, atomic::Ordering::SeqCst);
+    let err = runtime.block_on(connection.refresh_topology()).unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::ResponseError);
+    let port = runtime.block_on(cmd("GET").arg("foo").query_async::<_, u16>(&mut connection));
+    assert_eq!(port, Ok(6380));
+}
+
+#[test]
+fn connection_pool_grows_per_node() {
+    let _ = env_logger::try_init();
+    let name = "connection_pool_grows_per_node";
+
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        // Keep every request in flight so that each one wants an idle connection
+        Err(Ok(Value::Status(STALL.into())))
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_connections_per_node(2)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+
+    for _ in 0..3 {
+        let mut connection = connection.clone();
+        runtime.spawn(async move {
+            let _ = cmd("GET")
+                .arg("test")
+                .query_async::<_, Option<i32>>(&mut connection)
+                .await;
+        });
+    }
+
+    let stats = runtime.block_on(async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        connection.pool_stats().await
+    });
+    let stats = stats.unwrap();
+    let stats = &stats[&format!("{}:6379", name)];
+    assert_eq!(stats.open(), 2);
+    assert_eq!(stats.idle(), 0);
+}
+
+#[test]
+fn dropped_requests_are_cancelled() {
+    let _ = env_logger::try_init();
+    let name = "dropped_requests_are_cancelled";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        respond_startup_two_nodes(name, cmd)?;
+        if contains_slice(cmd, b"stall") {
+            return Err(Ok(Value::Status(STALL.into())));
+        }
+        Err(Ok(Value::Int(port.into())))
+    });
+
+    runtime.block_on(async {
+        let requests = (0..1000).map(|i| {
+            let mut connection = connection.clone();
+            async move {
+                // Split over both nodes every fourth request
+                let mut request = cmd(if i % 4 == 0 { "MGET" } else { "GET" });
+                request.arg(format!("stall{{foo}}{}", i));
+                if i % 4 == 0 {
+                    request.arg(format!("stall{{bar}}{}", i));
+                }
+                request.query_async::<_, Value>(&mut connection).await
+            }
+        });
+        let result =
+            tokio::time::timeout(Duration::from_millis(50), future::join_all(requests)).await;
+        assert!(result.is_err());
+    });
+
+    let mut connection = connection;
+    for i in 0..100 {
+        let (key, port) = if i % 2 == 0 {
+            ("foo", 6380)
+        } else {
+            ("bar", 6379)
+        };
+        let value = runtime.block_on(cmd("GET").arg(key).query_async::<_, u16>(&mut connection));
+        assert_eq!(value, Ok(port));
+    }
+    let stats = runtime.block_on(connection.pool_stats()).unwrap();
+    for port in [6379, 6380] {
+        let stats = &stats[&format!("{}:{}", name, port)];
+        assert_eq!(stats.idle(), stats.open());
+    }
+}
+
+#[test]
+fn ping_all_reports_each_master() {
+    let _ = env_logger::try_init();
+    let name = "ping_all_reports_each_master";
+
+    let stalled = Arc::new(atomic::AtomicBool::new(false));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let stalled = stalled.clone();
+        move |cmd: &[u8], port| {
+            if port == 6380
+                && contains_slice(cmd, b"PING")
+                && stalled.load(atomic::Ordering::SeqCst)
+            {
+                return Err(Ok(Value::Status(STALL.into())));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_response_timeout(Some(Duration::from_millis(50)))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    // Connect to both nodes before the second one stops answering
+    runtime.block_on(connection.warm_up()).unwrap();
+    stalled.store(true, atomic::Ordering::SeqCst);
+
+    let results = runtime.block_on(connection.ping_all()).unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].0, format!("{}:6379", name));
+    assert!(results[0].1.is_ok());
+    assert_eq!(results[1].0, format!("{}:6380", name));
+    assert!(results[1].1.as_ref().unwrap_err().is_timeout());
+    assert!(!runtime.block_on(connection.is_healthy()));
+
+    stalled.store(false, atomic::Ordering::SeqCst);
+    assert!(runtime.block_on(connection.is_healthy()));
+}
+
+#[test]
+fn warm_up_opens_the_connections_of_every_node() {
+    let _ = env_logger::try_init();
+    let name = "warm_up_opens_the_connections_of_every_node";
+
+    // The ports whose `PING` fails
+    let failing = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let failing = failing.clone();
+        move |cmd: &[u8], port| {
+            if contains_slice(cmd, b"PING") && failing.lock().unwrap().contains(&port) {
+                return Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionRefused,
+                )
+                .into()));
+            }
+            respond_startup_two_nodes(name, cmd)?;
+            Err(Ok(Value::Int(port.into())))
+        }
+    });
+
+    let open = |connection: &redis_cluster_async::Connection<MockConnection>| {
+        let stats = runtime.block_on(connection.pool_stats()).unwrap();
+        let mut open: Vec<_> = stats
+            .iter()
+            .map(|(addr, stats)| (addr.clone(), stats.open()))
+            .collect();
+        open.sort();
+        open
+    };
+    let connection = runtime
+        .block_on(
+            client
+                .set_connections_per_node(2)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    assert_eq!(
+        open(&connection),
+        [(format!("{}:6379", name), 1), (format!("{}:6380", name), 1)]
+    );
+    runtime.block_on(connection.warm_up()).unwrap();
+    assert_eq!(
+        open(&connection),
+        [(format!("{}:6379", name), 2), (format!("{}:6380", name), 2)]
+    );
+
+    // A single reachable node is enough
+    let connection = runtime
+        .block_on(
+            client
+                .set_connections_per_node(3)
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    failing.lock().unwrap().push(6380);
+    runtime.block_on(connection.warm_up()).unwrap();
+
+    let connection = runtime
+        .block_on(client.get_generic_connection::<MockConnection>())
+        .unwrap();
+    failing.lock().unwrap().push(6379);
+    let err = runtime.block_on(connection.warm_up()).unwrap_err();
+    assert_eq!(err.kind(), redis::ErrorKind::IoError);
+}
+
+fn scan_reply(cursor: &str, items: &[&str]) -> Result<(), RedisResult<Value>> {
+    Err(Ok(Value::Bulk(vec![
+        Value::Data(cursor.as_bytes().to_vec()),
+        Value::Bulk(
+            items
+                .iter()
+                .map(|item| Value::Data(item.as_bytes().to_vec()))
+                .collect(),
+        ),
+    ])))
+}
+
+#[test]
+fn cluster_scan_continues_after_node_failure() {
+    let _ = env_logger::try_init();
+    let name = "cluster_scan_continues_after_node_failure";
+
+    let failed = atomic::AtomicBool::new(false);
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], port| {
+        if failed.load(atomic::Ordering::SeqCst)
+            && contains_slice(cmd, b"CLUSTER")
+            && contains_slice(cmd, b"SLOTS")
+        {
+            // The slots of 6380 were taken over by 6381
+            return Err(Ok(Value::Bulk(vec![
+                Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(8191),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6379),
+                    ]),
+                ]),
+                Value::Bulk(vec![
+                    Value::Int(8192),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(name.as_bytes().to_vec()),
+                        Value::Int(6381),
+                    ]),
+                ]),
+            ])));
+        }
+        respond_startup_two_nodes(name, cmd)?;
+        let args = match parse_redis_value(cmd) {
+            Ok(Value::Bulk(args)) => args,
+            _ => panic!("Invalid command"),
+        };
+        assert_eq!(args[0], Value::Data(b"SCAN".to_vec()));
+        assert_eq!(
+            args[2..],
+            [
+                Value::Data(b"MATCH".to_vec()),
+                Value::Data(b"key*".to_vec())
+            ]
+        );
+        match (port, &args[1]) {
+            (6379, Value::Data(cursor)) if cursor == b"0" => scan_reply("7", &["key1"]),
+            (6379, _) => scan_reply("0", &["key2"]),
+            (6380, _) => {
+                failed.store(true, atomic::Ordering::SeqCst);
+                Err(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                )
+                .into()))
+            }
+            (6381, _) => scan_reply("0", &["key3"]),
+            _ => panic!("Unexpected port {}", port),
+        }
+    });
+
+    let connection = runtime
+        .block_on(
+            client
+                .set_retries(Some(0))
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let mut keys = runtime
+        .block_on(
+            connection
+                .scan(ScanOptions::new().with_pattern("key*"))
+                .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<RedisResult<Vec<_>>>()
+        .unwrap();
+    keys.sort();
+    assert_eq!(keys, [b"key1", b"key2", b"key3"]);
+}
+
+#[test]
+fn cluster_scan_returns_binary_keys() {
+    let _ = env_logger::try_init();
+    let name = "cluster_scan_returns_binary_keys";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        Err(Ok(Value::Bulk(vec![
+            Value::Data(b"0".to_vec()),
+            Value::Bulk(vec![
+                Value::Data(b"key\xff\xfe".to_vec()),
+                Value::Data(b"key1".to_vec()),
+            ]),
+        ])))
+    });
+
+    let keys = runtime
+        .block_on(connection.scan(ScanOptions::new()).collect::<Vec<_>>())
+        .into_iter()
+        .collect::<RedisResult<Vec<_>>>()
+        .unwrap();
+    assert_eq!(keys, [b"key\xff\xfe".to_vec(), b"key1".to_vec()]);
+}
+
+#[test]
+fn scan_del_unlinks_the_matching_keys_of_every_master() {
+    let _ = env_logger::try_init();
+    let name = "scan_del_unlinks_the_matching_keys_of_every_master";
+
+    let unlinked = Arc::new(Mutex::new(Vec::new()));
+    let moved = atomic::AtomicBool::new(false);
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let unlinked = unlinked.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let args = match parse_redis_value(cmd) {
+                Ok(Value::Bulk(args)) => args,
+                _ => panic!("Invalid command"),
+            };
+            match &args[0] {
+                Value::Data(scan) if scan == b"SCAN" => {
+                    assert_eq!(
+                        args[2..],
+                        [
+                            Value::Data(b"MATCH".to_vec()),
+                            Value::Data(b"tmp*".to_vec()),
+                            Value::Data(b"COUNT".to_vec()),
+                            Value::Data(b"2".to_vec()),
+                        ]
+                    );
+                    match port {
+                        6379 => scan_reply("0", &["tmp{bar}1", "tmp{bar}2", "tmp3"]),
+                        _ => scan_reply("0", &["tmp{foo}"]),
+                    }
+                }
+                Value::Data(unlink) if unlink == b"UNLINK" => {
+                    // The slot of `foo` is being migrated to 6379
+                    if port == 6380 && !moved.swap(true, atomic::Ordering::SeqCst) {
+                        return Err(parse_redis_value(
+                            format!("-ASK 12182 {}:6379\r\n", name).as_bytes(),
+                        ));
+                    }
+                    let keys: Vec<Vec<u8>> = args[1..]
+                        .iter()
+                        .map(|key| match key {
+                            Value::Data(key) => key.clone(),
+                            _ => panic!("Invalid key"),
+                        })
+                        .collect();
+                    let count = keys.len() as i64;
+                    unlinked.lock().unwrap().push((port, keys));
+                    Err(Ok(Value::Int(count)))
+                }
+                Value::Data(asking) if asking == b"ASKING" => Err(Ok(Value::Okay)),
+                _ => panic!("Unexpected command {:?}", args),
+            }
+        }
+    });
+
+    let deleted = runtime.block_on(connection.scan_del("tmp*", 2)).unwrap();
+    assert_eq!(deleted, 4);
+    let mut unlinked = unlinked.lock().unwrap().clone();
+    unlinked.sort();
+    assert_eq!(
+        unlinked,
+        [
+            (6379, vec![b"tmp3".to_vec()]),
+            (6379, vec![b"tmp{bar}1".to_vec(), b"tmp{bar}2".to_vec()]),
+            (6379, vec![b"tmp{foo}".to_vec()]),
+        ]
+    );
+}
+
+#[test]
+fn scan_del_groups_the_keys_by_the_configured_slots() {
+    let _ = env_logger::try_init();
+    let name = "scan_del_groups_the_keys_by_the_configured_slots";
+
+    let unlinked = Arc::new(Mutex::new(Vec::new()));
+    let MockEnv {
+        runtime,
+        mut client,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, {
+        let unlinked = unlinked.clone();
+        move |cmd: &[u8], port| {
+            respond_startup_two_nodes(name, cmd)?;
+            let args = match parse_redis_value(cmd) {
+                Ok(Value::Bulk(args)) => args,
+                _ => panic!("Invalid command"),
+            };
+            match &args[0] {
+                Value::Data(scan) if scan == b"SCAN" => {
+                    let keys: &[&[u8]] = match port {
+                        6379 => &[b"bar\xff1", b"bar\xfe2"],
+                        _ => &[b"foo\x80"],
+                    };
+                    Err(Ok(Value::Bulk(vec![
+                        Value::Data(b"0".to_vec()),
+                        Value::Bulk(keys.iter().map(|key| Value::Data(key.to_vec())).collect()),
+                    ])))
+                }
+                Value::Data(unlink) if unlink == b"UNLINK" => {
+                    let count = args.len() as i64 - 1;
+                    unlinked.lock().unwrap().push((port, args[1..].to_vec()));
+                    Err(Ok(Value::Int(count)))
+                }
+                _ => panic!("Unexpected command {:?}", args),
+            }
+        }
+    });
+
+    // The slot of a key is the one of its first three bytes
+    let connection = runtime
+        .block_on(
+            client
+                .set_hash_key(|key| &key[..key.len().min(3)])
+                .get_generic_connection::<MockConnection>(),
+        )
+        .unwrap();
+    let deleted = runtime.block_on(connection.scan_del("*", 10)).unwrap();
+    assert_eq!(deleted, 3);
+    let mut unlinked = unlinked.lock().unwrap().clone();
+    unlinked.sort_by_key(|(port, _)| *port);
+    assert_eq!(
+        unlinked,
+        [
+            (
+                6379,
+                vec![
+                    Value::Data(b"bar\xff1".to_vec()),
+                    Value::Data(b"bar\xfe2".to_vec())
+                ]
+            ),
+            (6380, vec![Value::Data(b"foo\x80".to_vec())]),
+        ]
+    );
+}
+
+#[test]
+fn hscan_iterates_the_key() {
+    let _ = env_logger::try_init();
+    let name = "hscan_iterates_the_key";
+
+    let MockEnv {
+        runtime,
+        connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        if contains_slice(cmd, b"$1\r\n0\r\n") {
+            scan_reply("3", &["field1", "value1"])
+        } else {
+            scan_reply("0", &["field2", "value2"])
+        }
+    });
+
+    let fields = runtime
+        .block_on(
+            connection
+                .hscan::<_, (String, String)>("hash", ScanOptions::new().with_count(1))
+                .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<RedisResult<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        fields,
+        [
+            ("field1".to_string(), "value1".to_string()),
+            ("field2".to_string(), "value2".to_string())
+        ]
+    );
+}
+
+#[test]
+fn migrate_key_restores_the_dump_on_the_target() {
+    let _ = env_logger::try_init();
+    let name = "migrate_key_restores_the_dump_on_the_target";
+    let target_name = "migrate_key_target";
+    // Serialized values are binary
+    const PAYLOAD: &[u8] = b"\x00\r\n\xff\x0a";
+
+    let MockEnv {
+        runtime,
+        mut connection,
+        handler: _handler,
+        ..
+    } = MockEnv::new(name, move |cmd: &[u8], _| {
+        respond_startup(name, cmd)?;
+        let missing = contains_slice(cmd, b"missing");
+        if contains_slice(cmd, b"DUMP") {
+            Err(Ok(if missing {
+                Value::Nil
+            } else {
+                Value::Data(PAYLOAD.to_vec())
+            }))
+        } else {
+            Err(Ok(Value::Int(if missing { -2 } else { 1500 })))
+        }
+    });
+
+    let restores = Arc::new(Mutex::new(Vec::new()));
+    HANDLERS.write().unwrap().insert(target_name.to_string(), {
+        let restores = restores.clone();
+        Arc::new(move |cmd, _| {
+            let cmd = cmd.get_packed_command();
+            respond_startup(target_name, &cmd)?;
+            restores.lock().unwrap().push(cmd);
+            Err(Ok(Value::Okay))
+        })
+    });
+    let _target_handler = RemoveHandler(target_name.to_string());
+    let target_client = Client::open(vec![&*format!("redis://{}", target_name)]).unwrap();
+    let mut target = runtime
+        .block_on(target_client.get_generic_connection::<MockConnection>())
+        .unwrap();
+
+    let options = RestoreOptions::new().with_replace().with_idle_time(10);
+    let migrated = runtime.block_on(connection.migrate_key("foo", &mut target, options.clone()));
+    assert_eq!(migrated, Ok(true));
+    let migrated = runtime.block_on(connection.migrate_key("missing", &mut target, options));
+    assert_eq!(migrated, Ok(false));
+
+    let expected = cmd("RESTORE")
+        .arg("foo")
+        .arg(1500)
+        .arg(PAYLOAD)
+        .arg("REPLACE")
+        .arg("IDLETIME")
+        .arg(10)
+        .get_packed_command();
+    assert_eq!(*restores.lock().unwrap(), [expected]);
+}
+
+// Reads from a fake node until `cmd` has been received
+async fn expect_command(socket: &mut tokio::net::TcpStream, cmd: &redis::Cmd) {
+    expect_commands(socket, &[cmd]).await
+}
+
+// Read from `socket` until each of `cmds` was received, in any order
+async fn expect_commands(socket: &mut tokio::net::TcpStream, cmds: &[&redis::Cmd]) {
+    use tokio::io::AsyncReadExt;
+
+    let expected: Vec<_> = cmds.iter().map(|cmd| cmd.get_packed_command()).collect();
+    let mut received = Vec::new();
+    while !expected
+        .iter()
+        .all(|expected| contains_slice(&received, expected))
+    {
+        let mut buf = [0; 1024];
+        let read = socket.read(&mut buf).await.unwrap();
+        assert!(read > 0, "Connection closed before the expected command");
+        received.extend_from_slice(&buf[..read]);
+    }
+}
+
+fn message(channel: &str, payload: &str) -> Vec<u8> {
+    format!(
+        "*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+        channel.len(),
+        channel,
+        payload.len(),
+        payload
+    )
+    .into_bytes()
+}
+
+#[test]
+fn pubsub_resubscribes_after_reconnect() {
+    use tokio::io::AsyncWriteExt;
+
+    let _ = env_logger::try_init();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::open(vec![format!("redis://127.0.0.1:{}", port)]).unwrap();
+        client.set_retry_policy(RetryPolicy::Fixed(Duration::from_millis(10)));
+
+        let node = tokio::spawn(async move {
+            let subscribe = cmd("SUBSCRIBE").arg("test").clone();
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            expect_command(&mut socket, &subscribe).await;
+            socket.write_all(&message("test", "first")).await.unwrap();
+            drop(socket);
+
+            // The subscription must be made again on the new connection
+            let (mut socket, _) = listener.accept().await.unwrap();
+            expect_command(&mut socket, &subscribe).await;
+            socket.write_all(&message("test", "second")).await.unwrap();
+            socket
+        });
+
+        let mut pubsub = client.get_pubsub().await.unwrap();
+        pubsub.subscribe("test").await.unwrap();
+
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_channel_name(), "test");
+        assert_eq!(msg.get_payload::<String>().unwrap(), "first");
+        assert!(!msg.from_pattern());
+
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_payload::<String>().unwrap(), "second");
+        let _socket = node.await.unwrap();
+    });
+}
+
+#[test]
+fn pubsub_reports_reconnects() {
+    use tokio::io::AsyncWriteExt;
+
+    let _ = env_logger::try_init();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::open(vec![format!("redis://127.0.0.1:{}", port)]).unwrap();
+        client.set_retry_policy(RetryPolicy::Fixed(Duration::from_millis(10)));
+
+        let node = tokio::spawn(async move {
+            let subscribe = cmd("SUBSCRIBE").arg("test").clone();
+            let psubscribe = cmd("PSUBSCRIBE").arg("test.*").clone();
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            expect_commands(&mut socket, &[&subscribe, &psubscribe]).await;
+            socket.write_all(&message("test", "first")).await.unwrap();
+            drop(socket);
+
+            // Both the channel and the pattern are subscribed to again
+            let (mut socket, _) = listener.accept().await.unwrap();
+            expect_commands(&mut socket, &[&subscribe, &psubscribe]).await;
+            socket.write_all(&message("test", "second")).await.unwrap();
+            socket
+        });
+
+        let reconnects = Arc::new(atomic::AtomicUsize::new(0));
+        let mut pubsub = client.get_pubsub().await.unwrap();
+        pubsub.on_reconnect({
+            let reconnects = reconnects.clone();
+            move || {
+                reconnects.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+        });
+        pubsub.subscribe("test").await.unwrap();
+        pubsub.psubscribe("test.*").await.unwrap();
+
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_payload::<String>().unwrap(), "first");
+        assert_eq!(reconnects.load(atomic::Ordering::SeqCst), 0);
+
+        // The callback runs before the messages of the new connection are yielded
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_payload::<String>().unwrap(), "second");
+        assert_eq!(reconnects.load(atomic::Ordering::SeqCst), 1);
+        let _socket = node.await.unwrap();
+    });
+}
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Nil => b"$-1\r\n".to_vec(),
+        Value::Int(i) => format!(":{}\r\n", i).into_bytes(),
+        Value::Data(data) => {
+            let mut out = format!("${}\r\n", data.len()).into_bytes();
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+        Value::Bulk(items) => {
+            let mut out = format!("*{}\r\n", items.len()).into_bytes();
+            for item in items {
+                out.extend(encode_value(item));
+            }
+            out
+        }
+        Value::Status(status) => format!("+{}\r\n", status).into_bytes(),
+        Value::Okay => b"+OK\r\n".to_vec(),
+    }
+}
+
+fn data_array(items: &[&[u8]]) -> Value {
+    Value::Bulk(
+        items
+            .iter()
+            .map(|item| Value::Data(item.to_vec()))
+            .collect(),
+    )
+}
+
+fn subscription_reply(kind: &[u8], channel: &[u8], count: i64) -> Value {
+    Value::Bulk(vec![
+        Value::Data(kind.to_vec()),
+        Value::Data(channel.to_vec()),
+        Value::Int(count),
+    ])
+}
+
+// Serves a fake node over TCP, each command received is answered with the values returned by
+// `respond`
+async fn serve_fake_node<F>(listener: tokio::net::TcpListener, respond: F)
+where
+    F: Fn(&[Vec<u8>]) -> Vec<Value> + Send + Sync + 'static,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let respond = Arc::new(respond);
+    loop {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let respond = respond.clone();
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            loop {
+                let mut buf = [0; 1024];
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => received.extend_from_slice(&buf[..read]),
+                }
+                // Commands are arrays of bulk strings, which encode back to the same bytes
+                while let Ok(value) = parse_redis_value(&received) {
+                    received.drain(..encode_value(&value).len());
+                    let args = match value {
+                        Value::Bulk(args) => args,
+                        _ => panic!("Unexpected command {:?}", value),
+                    };
+                    let args = args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            Value::Data(arg) => arg,
+                            _ => panic!("Unexpected argument {:?}", arg),
+                        })
+                        .collect::<Vec<_>>();
+                    for value in respond(&args) {
+                        if socket.write_all(&encode_value(&value)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn sharded_pubsub_follows_slot_migration() {
+    let _ = env_logger::try_init();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let first = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_port = first.local_addr().unwrap().port();
+        let second_port = second.local_addr().unwrap().port();
+        let owner = Arc::new(atomic::AtomicU16::new(first_port));
+
+        let node = |port: u16, owner: Arc<atomic::AtomicU16>| {
+            move |args: &[Vec<u8>]| match &args[0][..] {
+                b"PING" => vec![Value::Status("PONG".into())],
+                b"CLUSTER" => vec![Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(b"127.0.0.1".to_vec()),
+                        Value::Int(owner.load(atomic::Ordering::SeqCst).into()),
+                    ]),
+                ])])],
+                b"SSUBSCRIBE" if port == first_port => {
+                    // The slot of the channel moves to the second node right after the first
+                    // message
+                    owner.store(second_port, atomic::Ordering::SeqCst);
+                    vec![
+                        subscription_reply(b"ssubscribe", b"test", 1),
+                        data_array(&[b"smessage", b"test", b"first"]),
+                        subscription_reply(b"sunsubscribe", b"test", 0),
+                    ]
+                }
+                b"SSUBSCRIBE" => vec![
+                    subscription_reply(b"ssubscribe", b"test", 1),
+                    data_array(&[b"smessage", b"test", b"second"]),
+                ],
+                _ => panic!("Unexpected command {:?}", args),
+            }
+        };
+        tokio::spawn(serve_fake_node(first, node(first_port, owner.clone())));
+        tokio::spawn(serve_fake_node(second, node(second_port, owner)));
+
+        let client = Client::open(vec![format!("redis://127.0.0.1:{}", first_port)]).unwrap();
+        let mut pubsub = client.get_spubsub().await.unwrap();
+        pubsub.ssubscribe("test").await.unwrap();
+
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_channel_name(), "test");
+        assert_eq!(msg.get_payload::<String>().unwrap(), "first");
+
+        let msg = pubsub.next().await.unwrap();
+        assert_eq!(msg.get_payload::<String>().unwrap(), "second");
+    });
+}
+
+#[test]
+fn monitor_streams_the_commands_of_the_node() {
+    let _ = env_logger::try_init();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_fake_node(
+            listener,
+            move |args: &[Vec<u8>]| match &args[0][..] {
+                b"PING" => vec![Value::Status("PONG".into())],
+                b"CLUSTER" => vec![Value::Bulk(vec![Value::Bulk(vec![
+                    Value::Int(0),
+                    Value::Int(16383),
+                    Value::Bulk(vec![
+                        Value::Data(b"127.0.0.1".to_vec()),
+                        Value::Int(port.into()),
+                    ]),
+                ])])],
+                b"MONITOR" => vec![
+                    Value::Okay,
+                    Value::Status(r#"1700000000.000001 [0 127.0.0.1:5000] "GET" "foo""#.into()),
+                    Value::Status(r#"1700000000.000002 [0 127.0.0.1:5000] "DEL" "bar""#.into()),
+                ],
+                _ => panic!("Unexpected command {:?}", args),
+            },
+        ));
+
+        let client = Client::open(vec![format!("redis://127.0.0.1:{}", port)]).unwrap();
+        let connection = client.get_connection().await.unwrap();
+        let lines = connection
+            .monitor(&format!("127.0.0.1:{}", port))
+            .take(2)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            lines,
+            [
+                Ok(r#"1700000000.000001 [0 127.0.0.1:5000] "GET" "foo""#.to_string()),
+                Ok(r#"1700000000.000002 [0 127.0.0.1:5000] "DEL" "bar""#.to_string()),
+            ]
+        );
+
+        let err = connection
+            .monitor("127.0.0.1:1")
+            .next()
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+    });
+}